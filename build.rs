@@ -8,12 +8,27 @@ fn main()
   let mut cfg = Cfg::new();
   cfg.minify_css = true;
   cfg.minify_js = true;
-  std::fs::write(
-    "templates/login.html",
-    minify(&std::fs::read("templates/src/login.html").unwrap(), &cfg),
-  )
-  .unwrap();
-  println!("cargo:rerun-if-changed=templates/src/login.html");
+  for template in [
+    "base.html",
+    "login.html",
+    "security_keys.html",
+    "account_password.html",
+    "account_mfa.html",
+    "account_mfa_done.html",
+    "invite.html",
+    "verify.html",
+  ]
+  {
+    std::fs::write(
+      format!("templates/{template}"),
+      minify(
+        &std::fs::read(format!("templates/src/{template}")).unwrap(),
+        &cfg,
+      ),
+    )
+    .unwrap();
+    println!("cargo:rerun-if-changed=templates/src/{template}");
+  }
 
   let sample_config = toml::to_string(&config::Settings::default()).unwrap();
   std::fs::write("pkg/ruuth.toml.default", sample_config).unwrap();
@@ -0,0 +1,162 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::config::{AccessDefault, AccessRule, AccessSettings};
+
+/// Host and path of the resource nginx is asking about
+#[derive(Debug)]
+pub struct Target
+{
+  host: String,
+  /// Unknown when nginx didn't send a usable X-Original-URL
+  path: Option<String>,
+}
+
+impl Target
+{
+  /// Prefers the full URL from X-Original-URL, falling back to the Host header when nginx
+  /// was not configured to send it
+  pub fn new(original_url: Option<&str>, host: Option<&str>) -> Self
+  {
+    match original_url.and_then(|url| Url::parse(url).ok())
+    {
+      Some(url) => Self {
+        host: normalize_host(url.host_str().unwrap_or("")),
+        path: Some(normalize_path(url.path())),
+      },
+      None => Self {
+        host: normalize_host(host.unwrap_or("")),
+        path: None,
+      },
+    }
+  }
+}
+
+fn normalize_host(host: &str) -> String
+{
+  let host = match host.rsplit_once(':')
+  {
+    Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+    _ => host,
+  };
+  host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Decodes and resolves the path the way the application behind nginx would see it, so rules
+/// can't be dodged with tricks like /%61dmin or /public/../admin
+fn normalize_path(path: &str) -> String
+{
+  let decoded = percent_decode_str(path).decode_utf8_lossy();
+  let mut segments = Vec::new();
+  for segment in decoded.split(&['/', '\\'][..])
+  {
+    match segment
+    {
+      "" | "." =>
+      {}
+      ".." =>
+      {
+        segments.pop();
+      }
+      segment => segments.push(segment),
+    }
+  }
+  format!("/{}", segments.join("/"))
+}
+
+/// Whether a host matches an exact name or a `*.domain` pattern, which covers subdomains but not
+/// the domain itself
+pub fn host_matches(pattern: &str, host: &str) -> bool
+{
+  match pattern.strip_prefix("*.")
+  {
+    Some(domain) => host
+      .strip_suffix(domain)
+      .map_or(false, |subdomain| subdomain.ends_with('.')),
+    None => pattern == host,
+  }
+}
+
+fn path_matches(prefix: &str, path: &str) -> bool
+{
+  let prefix = prefix.trim_end_matches('/');
+  path
+    .strip_prefix(prefix)
+    .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Clone)]
+pub struct AccessPolicy
+{
+  default: AccessDefault,
+  rules: Vec<AccessRule>,
+}
+
+impl AccessPolicy
+{
+  pub fn new(settings: AccessSettings) -> Self
+  {
+    Self {
+      default: settings.default,
+      rules: settings
+        .rules
+        .into_iter()
+        .map(|rule| AccessRule {
+          host: rule.host.trim_end_matches('.').to_ascii_lowercase(),
+          path: normalize_path(&rule.path),
+          groups: rule.groups,
+        })
+        .collect(),
+    }
+  }
+
+  /// The most specific rule wins: exact hosts over wildcards, then the longest path.  Without
+  /// the path, a host with rules for particular paths is refused rather than judged as if the
+  /// root had been asked for
+  pub fn permits(&self, target: &Target, groups: &[String]) -> bool
+  {
+    let path = match &target.path
+    {
+      Some(path) => path.as_str(),
+      None
+        if self
+          .rules
+          .iter()
+          .any(|rule| host_matches(&rule.host, &target.host) && rule.path != "/") =>
+      {
+        return false
+      }
+      None => "/",
+    };
+    match self
+      .rules
+      .iter()
+      .filter(|rule| host_matches(&rule.host, &target.host) && path_matches(&rule.path, path))
+      .max_by_key(|rule| (!rule.host.starts_with("*."), rule.path.len()))
+    {
+      Some(rule) =>
+      {
+        rule.groups.is_empty() || rule.groups.iter().any(|group| groups.contains(group))
+      }
+      None => matches!(self.default, AccessDefault::Allow),
+    }
+  }
+}
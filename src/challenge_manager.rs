@@ -16,7 +16,12 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::time::{Duration, SystemTime};
+use std::{
+  collections::BTreeMap,
+  io::Cursor,
+  net::IpAddr,
+  time::{Duration, SystemTime},
+};
 
 use axum_sessions::{async_session::serde_json, extractors::WritableSession};
 use base64::{engine::general_purpose, Engine};
@@ -25,12 +30,15 @@ use captcha::{
   RngCaptcha,
 };
 use color_eyre::eyre::{eyre, Context, Result};
+use hound::{WavReader, WavWriter};
+use ipnet::IpNet;
 use rand::{rngs::ThreadRng, thread_rng, Rng};
 use rand_core::RngCore;
 use sea_orm::{
-  ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait,
-  QueryFilter, Set,
+  ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait,
+  QueryFilter, QueryOrder, Set,
 };
+use sha2::{Digest, Sha256};
 use tokio::{
   task::{self, JoinHandle},
   time,
@@ -38,8 +46,9 @@ use tokio::{
 use tracing::{event, instrument};
 
 use crate::{
-  config::BehaviourSettings,
-  entities::{ban_tracker, prelude::*},
+  config::{BehaviourSettings, CaptchaFilter, CaptchaSettings, ChallengeKind, DenyAction},
+  entities::{ban_tracker, manual_ban, prelude::*},
+  ip_lists::IpLists,
   session::WritableSessionExt,
 };
 
@@ -50,61 +59,467 @@ pub struct Base64Image
   pub base64: String,
 }
 
+/// A hashcash style puzzle solved by script on the login page
+pub struct ProofOfWork
+{
+  pub nonce: String,
+  pub difficulty: u32,
+}
+
+pub enum Challenge
+{
+  Captcha(Base64Image),
+  ProofOfWork(ProofOfWork),
+}
+
+/// The form an authenticity token was issued for.  Each is kept under its own key, so a token
+/// from a page that never shows a captcha can't be spent on the login form
+#[derive(Clone, Copy, Debug)]
+pub enum TokenScope
+{
+  Login,
+  Verify,
+  Password,
+  Mfa,
+  Invite,
+}
+
+impl TokenScope
+{
+  fn session_key(self) -> &'static str
+  {
+    match self
+    {
+      Self::Login => "authenticity_token",
+      Self::Verify => "verify_authenticity_token",
+      Self::Password => "password_authenticity_token",
+      Self::Mfa => "mfa_authenticity_token",
+      Self::Invite => "invite_authenticity_token",
+    }
+  }
+}
+
+/// Recent failures from one address
+pub struct BanSummary
+{
+  pub host: String,
+  pub failures: u64,
+  pub minutes_since_last: i64,
+}
+
+pub struct ManualBanSummary
+{
+  pub network: String,
+  /// None for bans that never expire
+  pub minutes_remaining: Option<i64>,
+}
+
+pub struct BanReport
+{
+  pub failures: u64,
+  pub subnet: String,
+  pub subnet_failures: u64,
+  pub usernames: Vec<String>,
+  pub manual_bans: Vec<ManualBanSummary>,
+  pub captcha: bool,
+  pub fake_login: bool,
+}
+
 #[derive(Clone)]
 pub struct ChallengeManager<const N: usize>
 {
   db: DatabaseConnection,
   thresholds: BehaviourSettings,
+  captcha: CaptchaSettings,
+  ip_lists: IpLists,
 }
 
 impl<const N: usize> ChallengeManager<N>
 {
-  pub async fn new(db: DatabaseConnection, thresholds: BehaviourSettings) -> Self
+  pub async fn new(
+    db: DatabaseConnection,
+    thresholds: BehaviourSettings,
+    captcha: CaptchaSettings,
+  ) -> Result<Self>
+  {
+    if thresholds.ipv4_prefix > 32 || thresholds.ipv6_prefix > 128
+    {
+      return Err(eyre!(
+        "ipv4_prefix must be at most 32 and ipv6_prefix at most 128"
+      ));
+    }
+    if captcha.min_length == 0 || captcha.min_length > captcha.max_length
+    {
+      return Err(eyre!(
+        "captcha min_length must be at least 1 and no more than max_length"
+      ));
+    }
+    if captcha.width == 0 || captcha.height == 0
+    {
+      return Err(eyre!("captcha width and height must be non-zero"));
+    }
+    if captcha
+      .charset
+      .as_ref()
+      .map_or(false, |charset| charset.is_empty())
+    {
+      return Err(eyre!("captcha charset must not be empty"));
+    }
+    for filter in &captcha.filters
+    {
+      Self::check_filter(filter)?;
+    }
+    if captcha.audio
+    {
+      // a recording can't tell upper and lower case apart, so a listener couldn't either
+      if captcha.case_sensitive
+      {
+        return Err(eyre!("captcha audio needs case_sensitive = false"));
+      }
+      let charset = captcha.charset.as_ref().ok_or_else(|| {
+        eyre!("captcha audio needs a charset, so every character can be checked for a recording")
+      })?;
+      if let Some(c) = charset.chars().find(|&c| Self::audio_clip(c).is_none())
+      {
+        return Err(eyre!("no audio available for captcha character {:?}", c));
+      }
+    }
+    Ok(Self {
+      db,
+      ip_lists: IpLists::new(&thresholds).wrap_err("invalid allow or deny list")?,
+      thresholds,
+      captcha,
+    })
+  }
+
+  fn subnet(&self, host: IpAddr) -> String
+  {
+    let prefix = match host
+    {
+      IpAddr::V4(_) => self.thresholds.ipv4_prefix,
+      IpAddr::V6(_) => self.thresholds.ipv6_prefix,
+    };
+    IpNet::new(host, prefix)
+      .map_or_else(|_| IpNet::from(host), |net| net.trunc())
+      .to_string()
+  }
+
+  /// How far the address or the subnet around it is past its threshold, whichever is further.
+  /// Checking the subnet means spreading attempts over a block of addresses doesn't reset the
+  /// count
+  async fn excess(
+    &self,
+    host: IpAddr,
+    threshold: Option<u64>,
+    subnet_threshold: Option<u64>,
+  ) -> Result<Option<u64>, DbErr>
+  {
+    if self.ip_lists.allowed(host)
+    {
+      return Ok(None);
+    }
+    let mut excess = None;
+    if let Some(threshold) = threshold
+    {
+      let failures = self
+        .failure_count(ban_tracker::Column::Host, &host.to_string())
+        .await?;
+      if failures > threshold
+      {
+        excess = Some(failures - threshold);
+      }
+    }
+    if let Some(threshold) = subnet_threshold.or(threshold)
+    {
+      let failures = self
+        .failure_count(ban_tracker::Column::Subnet, &self.subnet(host))
+        .await?;
+      if failures > threshold
+      {
+        excess = excess.max(Some(failures - threshold));
+      }
+    }
+    Ok(excess)
+  }
+
+  async fn exceeds(
+    &self,
+    host: IpAddr,
+    threshold: Option<u64>,
+    subnet_threshold: Option<u64>,
+  ) -> Result<bool, DbErr>
   {
-    Self { db, thresholds }
+    Ok(
+      self
+        .excess(host, threshold, subnet_threshold)
+        .await?
+        .is_some(),
+    )
   }
 
-  pub fn issue_challenge(&self, session: &mut WritableSession)
-    -> Result<String, serde_json::Error>
+  pub fn issue_challenge(
+    &self,
+    session: &mut WritableSession,
+    scope: TokenScope,
+  ) -> Result<String, serde_json::Error>
   {
     let mut challenge = [0; N];
     thread_rng().fill_bytes(&mut challenge);
     let token = general_purpose::STANDARD.encode(challenge);
-    session.insert("authenticity_token", &token)?;
+    session.insert(scope.session_key(), &token)?;
     Ok(token)
   }
 
-  pub async fn maybe_issue_captcha(
+  pub async fn maybe_issue_challenge(
     &self,
     session: &mut WritableSession,
-    host: &str,
-  ) -> Result<Option<Base64Image>>
+    host: IpAddr,
+  ) -> Result<Option<Challenge>>
   {
-    match self.thresholds.captcha
+    let excess = match self
+      .excess(
+        host,
+        self.thresholds.captcha,
+        self.thresholds.subnet_captcha,
+      )
+      .await?
+    {
+      Some(excess) => excess,
+      None => return Ok(None),
+    };
+
+    match self.thresholds.challenge
     {
-      Some(threshold) if self.failure_count(host).await? > threshold =>
+      ChallengeKind::Captcha => Ok(Some(Challenge::Captcha(self.issue_captcha(session)?))),
+      ChallengeKind::ProofOfWork => Ok(Some(Challenge::ProofOfWork(
+        self.issue_proof_of_work(session, excess)?,
+      ))),
+    }
+  }
+
+  fn issue_captcha(&self, session: &mut WritableSession) -> Result<Base64Image>
+  {
+    let settings = &self.captcha;
+    let num_chars = thread_rng().gen_range(settings.min_length..=settings.max_length);
+    let mut captcha = RngCaptcha::<ThreadRng>::from_rng(thread_rng());
+    if let Some(charset) = &settings.charset
+    {
+      captcha.set_chars(&charset.chars().collect::<Vec<_>>());
+    }
+    captcha.add_chars(num_chars);
+    // dots are drawn once the image is cropped and scaled, so they keep their size and don't
+    // get cropped away with the empty border
+    let (dots, distortions): (Vec<_>, Vec<_>) = settings
+      .filters
+      .iter()
+      .partition(|filter| matches!(filter, CaptchaFilter::Dots { .. }));
+    for filter in distortions
+    {
+      Self::apply_filter(&mut captcha, filter);
+    }
+    captcha.view(settings.width, settings.height);
+    for filter in dots
+    {
+      Self::apply_filter(&mut captcha, filter);
+    }
+    session
+      .insert("captcha_solution", captcha.chars_as_string())
+      .wrap_err("failed to insert captcha solution into session")?;
+    let base64 = captcha
+      .as_base64()
+      .ok_or_else(|| eyre!("error encoding png"))?;
+    Ok(Base64Image {
+      w: settings.width,
+      h: settings.height,
+      base64,
+    })
+  }
+
+  /// The captcha library doesn't check these itself, and would fail while drawing
+  fn check_filter(filter: &CaptchaFilter) -> Result<()>
+  {
+    match *filter
+    {
+      CaptchaFilter::Noise { probability } if !(0.0..=1.0).contains(&probability) =>
+      {
+        Err(eyre!("captcha noise probability must be between 0 and 1"))
+      }
+      CaptchaFilter::Grid { x_gap, y_gap } if x_gap == 0 || y_gap == 0 =>
+      {
+        Err(eyre!("captcha grid x_gap and y_gap must be non-zero"))
+      }
+      CaptchaFilter::Wave {
+        frequency,
+        amplitude,
+        ..
+      } if !frequency.is_finite() || !amplitude.is_finite() || amplitude < 0.0 => Err(eyre!(
+        "captcha wave frequency must be finite and amplitude finite and non-negative"
+      )),
+      CaptchaFilter::Dots {
+        min_radius,
+        max_radius,
+        ..
+      } if min_radius > max_radius => Err(eyre!(
+        "captcha dots min_radius must be no more than max_radius"
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  fn apply_filter(captcha: &mut RngCaptcha<ThreadRng>, filter: &CaptchaFilter)
+  {
+    match *filter
+    {
+      CaptchaFilter::Noise { probability } => captcha.apply_filter(Noise::new(probability as f32)),
+      CaptchaFilter::Grid { x_gap, y_gap } => captcha.apply_filter(Grid::new(y_gap, x_gap)),
+      CaptchaFilter::Wave {
+        frequency,
+        amplitude,
+        vertical,
+      } =>
+      {
+        let wave = Wave::new(frequency, amplitude);
+        captcha.apply_filter(
+          if vertical
+          {
+            wave.vertical()
+          }
+          else
+          {
+            wave.horizontal()
+          },
+        )
+      }
+      CaptchaFilter::Dots {
+        count,
+        min_radius,
+        max_radius,
+      } => captcha.apply_filter(
+        Dots::new(count)
+          .min_radius(min_radius)
+          .max_radius(max_radius),
+      ),
+    };
+  }
+
+  pub fn captcha_audio_enabled(&self) -> bool
+  {
+    self.captcha.audio
+  }
+
+  /// Reads out the captcha currently in the session, one recorded clip per character with a
+  /// pause between each
+  pub fn captcha_audio(&self, session: &WritableSession) -> Result<Option<Vec<u8>>>
+  {
+    if !self.captcha.audio
+    {
+      return Ok(None);
+    }
+    let solution = match session.get::<String>("captcha_solution")
+    {
+      Some(solution) => solution,
+      None => return Ok(None),
+    };
+
+    let mut clips = Vec::new();
+    for c in solution.chars()
+    {
+      match Self::audio_clip(c)
       {
-        let num_chars = thread_rng().gen_range(4..7);
-        let w = 220;
-        let h = 120;
-        let mut captcha = RngCaptcha::<ThreadRng>::from_rng(thread_rng());
-        captcha
-          .add_chars(num_chars)
-          .apply_filter(Noise::new(0.3))
-          .apply_filter(Grid::new(6, 6))
-          .apply_filter(Wave::new(2.0, 10.0))
-          .view(w, h)
-          .apply_filter(Dots::new(15).max_radius(7).min_radius(4));
-        session
-          .insert("captcha_solution", captcha.chars_as_string())
-          .wrap_err("failed to insert captcha solution into session")?;
-        let base64 = captcha
-          .as_base64()
-          .ok_or_else(|| eyre!("error encoding png"))?;
-        Ok(Some(Base64Image { w, h, base64 }))
+        Some(clip) => clips.push(clip),
+        None => return Err(eyre!("no audio available for captcha character {:?}", c)),
       }
-      _ => Ok(None),
     }
+    Ok(Some(
+      Self::join_clips(&clips).wrap_err("failed to encode captcha audio")?,
+    ))
+  }
+
+  fn audio_clip(c: char) -> Option<Vec<u8>>
+  {
+    let mut captcha = RngCaptcha::<ThreadRng>::from_rng(thread_rng());
+    captcha.set_chars(&[c]);
+    captcha.add_chars(1);
+    captcha.as_wav().pop().flatten()
+  }
+
+  fn join_clips(clips: &[Vec<u8>]) -> Result<Vec<u8>, hound::Error>
+  {
+    let spec = match clips.first()
+    {
+      Some(clip) => WavReader::new(Cursor::new(clip))?.spec(),
+      None => return Ok(Vec::new()),
+    };
+    let pause = (spec.sample_rate / 2) as usize * spec.channels as usize;
+    let mut output = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut output, spec)?;
+    for clip in clips
+    {
+      let mut reader = WavReader::new(Cursor::new(clip))?;
+      if reader.spec() != spec
+      {
+        return Err(hound::Error::Unsupported);
+      }
+      for sample in reader.samples::<i32>()
+      {
+        writer.write_sample(sample?)?;
+      }
+      for _ in 0..pause
+      {
+        writer.write_sample(0i32)?;
+      }
+    }
+    writer.finalize()?;
+    Ok(output.into_inner())
+  }
+
+  /// Each failure past the threshold adds a bit of difficulty, doubling the expected work
+  fn issue_proof_of_work(&self, session: &mut WritableSession, excess: u64) -> Result<ProofOfWork>
+  {
+    let extra = u32::try_from(excess - 1).unwrap_or(u32::MAX);
+    let difficulty = self
+      .thresholds
+      .pow_difficulty
+      .saturating_add(extra)
+      .min(self.thresholds.pow_difficulty_max);
+    let mut nonce = [0; N];
+    thread_rng().fill_bytes(&mut nonce);
+    let nonce = general_purpose::URL_SAFE_NO_PAD.encode(nonce);
+    session
+      .insert("proof_of_work", (&nonce, difficulty))
+      .wrap_err("failed to insert proof of work into session")?;
+    Ok(ProofOfWork { nonce, difficulty })
+  }
+
+  /// A proof is a counter for which sha256("<nonce>:<counter>") starts with at least
+  /// difficulty zero bits
+  fn proof_valid(nonce: &str, difficulty: u32, proof: &str) -> bool
+  {
+    if proof.is_empty() || proof.len() > 20 || !proof.bytes().all(|byte| byte.is_ascii_digit())
+    {
+      return false;
+    }
+    let mut zero_bits = 0;
+    for byte in Sha256::digest(format!("{}:{}", nonce, proof))
+    {
+      zero_bits += byte.leading_zeros();
+      if byte != 0
+      {
+        break;
+      }
+    }
+    zero_bits >= difficulty
+  }
+
+  /// Whether requests from this host should be refused outright rather than faked
+  pub fn forbidden(&self, host: IpAddr) -> bool
+  {
+    matches!(self.thresholds.deny_action, DenyAction::Forbidden) && self.ip_lists.denied(host)
+  }
+
+  pub fn reload_task(&self) -> JoinHandle<()>
+  {
+    self.ip_lists.reload_task()
   }
 
   pub fn cleanup_task(&self) -> JoinHandle<()>
@@ -125,6 +540,13 @@ impl<const N: usize> ChallengeManager<N>
         {
           event!(tracing::Level::ERROR, "{}", error);
         }
+        if let Err(error) = ManualBan::delete_many()
+          .filter(manual_ban::Column::Expires.lt(Self::now()))
+          .exec(&db)
+          .await
+        {
+          event!(tracing::Level::ERROR, "{}", error);
+        }
       }
     })
   }
@@ -141,52 +563,121 @@ impl<const N: usize> ChallengeManager<N>
     }
   }
 
+  pub fn validate_token(
+    &self,
+    session: &mut WritableSession,
+    scope: TokenScope,
+    token: &str,
+  ) -> bool
+  {
+    session
+      .take::<String>(scope.session_key())
+      .map_or(false, |authenticity_token| authenticity_token == token)
+  }
+
+  async fn banned(&self, host: IpAddr) -> Result<bool, DbErr>
+  {
+    Ok(
+      self
+        .exceeds(
+          host,
+          self.thresholds.fake_login,
+          self.thresholds.subnet_fake_login,
+        )
+        .await?
+        || self.ip_lists.denied(host)
+        || self.manually_banned(host).await?,
+    )
+  }
+
+  /// Whether attempts to prove who a user is should be refused without checking, for places
+  /// other than the login form that accept a password
+  #[instrument(skip(self))]
+  pub async fn throttled(&self, host: IpAddr, username: &str) -> Result<bool, DbErr>
+  {
+    Ok(self.banned(host).await? || self.account_locked(username).await?)
+  }
+
   #[instrument(skip(self))]
   pub async fn validate(
     &self,
     session: &mut WritableSession,
     token: &str,
     captcha_text: &Option<String>,
-    host: &str,
+    proof: &Option<String>,
+    host: IpAddr,
+    username: &str,
   ) -> Result<bool, DbErr>
   {
-    let csrf_valid = session
-      .take::<String>("authenticity_token")
-      .map_or(false, |authenticity_token| authenticity_token == token);
-    let captcha_valid = session
-      .take::<String>("captcha_solution")
-      .map_or(true, |solution| {
-        if let Some(captcha_text) = captcha_text
-        {
-          solution == *captcha_text
-        }
-        else
-        {
-          false
-        }
-      });
-    let banned = match self.thresholds.fake_login
-    {
-      Some(threshold) => self.failure_count(host).await? > threshold,
-      None => false,
-    };
-    let valid = csrf_valid && captcha_valid && !banned;
+    let csrf_valid = self.validate_token(session, TokenScope::Login, token);
+    // the challenge is required because of this host's failures, not because the session
+    // happens to hold one, so a session that was never shown it doesn't get to skip it
+    let challenge_required = self
+      .excess(
+        host,
+        self.thresholds.captcha,
+        self.thresholds.subnet_captcha,
+      )
+      .await?
+      .is_some();
+    let captcha_required =
+      challenge_required && matches!(self.thresholds.challenge, ChallengeKind::Captcha);
+    let proof_required =
+      challenge_required && matches!(self.thresholds.challenge, ChallengeKind::ProofOfWork);
+    let captcha_valid =
+      session
+        .take::<String>("captcha_solution")
+        .map_or(!captcha_required, |solution| {
+          if let Some(captcha_text) = captcha_text
+          {
+            let captcha_text = captcha_text.trim();
+            if self.captcha.case_sensitive
+            {
+              solution == captcha_text
+            }
+            else
+            {
+              solution.to_lowercase() == captcha_text.to_lowercase()
+            }
+          }
+          else
+          {
+            false
+          }
+        });
+    let proof_valid = session.take::<(String, u32)>("proof_of_work").map_or(
+      !proof_required,
+      |(nonce, difficulty)| {
+        proof
+          .as_deref()
+          .map_or(false, |proof| Self::proof_valid(&nonce, difficulty, proof))
+      },
+    );
+    let banned = self.banned(host).await?;
+    let locked = self.account_locked(username).await?;
+    let valid = csrf_valid && captcha_valid && proof_valid && !banned && !locked;
     event!(
       tracing::Level::INFO,
-      "csrf passed: {}, captcha passed: {}, banned: {}",
+      "csrf passed: {}, captcha passed: {}, proof of work passed: {}, banned: {}, account locked: {}",
       csrf_valid,
       captcha_valid,
-      banned
+      proof_valid,
+      banned,
+      locked
     );
     Ok(valid)
   }
 
   #[instrument(skip(self))]
-  pub async fn add_failure(&self, host: String) -> Result<(), DbErr>
+  pub async fn add_failure(&self, host: IpAddr, username: String) -> Result<(), DbErr>
   {
+    // the attempted username is recorded whether or not it exists, so lockouts behave
+    // identically for real and made up accounts
     ban_tracker::ActiveModel {
-      host: Set(host),
+      host: Set(host.to_string()),
       failure_timestamp: Set(Self::now()),
+      username: Set(Some(username)),
+      subnet: Set(Some(self.subnet(host))),
       ..Default::default()
     }
     .insert(&self.db)
@@ -194,12 +685,218 @@ impl<const N: usize> ChallengeManager<N>
     Ok(())
   }
 
+  /// Once an account passes its threshold, each further failure doubles how long it stays
+  /// locked after the most recent one
+  #[instrument(skip(self))]
+  async fn account_locked(&self, username: &str) -> Result<bool, DbErr>
+  {
+    let threshold = match self.thresholds.account_lockout
+    {
+      Some(threshold) => threshold,
+      None => return Ok(false),
+    };
+    let cutoff = i64::saturating_sub(Self::now(), self.thresholds.expiration);
+    let recent = BanTracker::find()
+      .filter(ban_tracker::Column::Username.eq(username))
+      .filter(ban_tracker::Column::FailureTimestamp.gte(cutoff));
+    let failures = recent.clone().count(&self.db).await?;
+    if failures < threshold
+    {
+      return Ok(false);
+    }
+
+    let last_failure = recent
+      .order_by_desc(ban_tracker::Column::FailureTimestamp)
+      .one(&self.db)
+      .await?
+      .map_or(cutoff, |failure| failure.failure_timestamp);
+    let exponent = (failures - threshold).min(32) as u32;
+    let backoff = self
+      .thresholds
+      .account_backoff
+      .saturating_mul(1 << exponent)
+      .min(self.thresholds.account_backoff_max);
+    event!(tracing::Level::INFO, "account failures: {}", failures);
+    Ok(Self::now() < last_failure.saturating_add(backoff))
+  }
+
+  async fn active_manual_bans(&self) -> Result<Vec<manual_ban::Model>, DbErr>
+  {
+    ManualBan::find()
+      .filter(
+        Condition::any()
+          .add(manual_ban::Column::Expires.is_null())
+          .add(manual_ban::Column::Expires.gte(Self::now())),
+      )
+      .all(&self.db)
+      .await
+  }
+
+  fn covering_bans(bans: Vec<manual_ban::Model>, host: IpAddr) -> Vec<manual_ban::Model>
+  {
+    bans
+      .into_iter()
+      .filter(|ban| {
+        ban
+          .network
+          .parse::<IpNet>()
+          .map_or(false, |network| network.contains(&host))
+      })
+      .collect()
+  }
+
+  #[instrument(skip(self))]
+  async fn manually_banned(&self, host: IpAddr) -> Result<bool, DbErr>
+  {
+    Ok(!Self::covering_bans(self.active_manual_bans().await?, host).is_empty())
+  }
+
+  fn summarize_manual_ban(ban: manual_ban::Model) -> ManualBanSummary
+  {
+    ManualBanSummary {
+      network: ban.network,
+      minutes_remaining: ban.expires.map(|expires| expires - Self::now()),
+    }
+  }
+
+  /// Bans a network until the duration (in minutes) passes, or forever
+  pub async fn ban(&self, network: IpNet, duration: Option<i64>) -> Result<()>
+  {
+    let network = network.trunc().to_string();
+    let expires = duration.map(|duration| Self::now().saturating_add(duration));
+    match ManualBan::find_by_id(network.clone()).one(&self.db).await?
+    {
+      Some(ban) =>
+      {
+        let mut ban: manual_ban::ActiveModel = ban.into();
+        ban.expires = Set(expires);
+        ban.update(&self.db).await?;
+      }
+      None =>
+      {
+        manual_ban::ActiveModel {
+          network: Set(network),
+          created: Set(Self::now()),
+          expires: Set(expires),
+        }
+        .insert(&self.db)
+        .await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Lifts a manual ban on exactly this network and forgets failures from addresses within it
+  pub async fn unban(&self, network: IpNet) -> Result<()>
+  {
+    let network = network.trunc();
+    ManualBan::delete_by_id(network.to_string())
+      .exec(&self.db)
+      .await?;
+    let ids: Vec<i64> = BanTracker::find()
+      .all(&self.db)
+      .await?
+      .into_iter()
+      .filter(|failure| {
+        failure
+          .host
+          .parse::<IpAddr>()
+          .map_or(false, |host| network.contains(&host))
+      })
+      .map(|failure| failure.id)
+      .collect();
+    if !ids.is_empty()
+    {
+      BanTracker::delete_many()
+        .filter(ban_tracker::Column::Id.is_in(ids))
+        .exec(&self.db)
+        .await?;
+    }
+    Ok(())
+  }
+
+  pub async fn list_bans(&self) -> Result<(Vec<ManualBanSummary>, Vec<BanSummary>)>
+  {
+    let manual_bans = self
+      .active_manual_bans()
+      .await?
+      .into_iter()
+      .map(Self::summarize_manual_ban)
+      .collect();
+
+    let cutoff = i64::saturating_sub(Self::now(), self.thresholds.expiration);
+    let mut hosts = BTreeMap::<String, BanSummary>::new();
+    for failure in BanTracker::find()
+      .filter(ban_tracker::Column::FailureTimestamp.gte(cutoff))
+      .all(&self.db)
+      .await?
+    {
+      let minutes_since = Self::now() - failure.failure_timestamp;
+      let summary = hosts
+        .entry(failure.host.clone())
+        .or_insert_with(|| BanSummary {
+          host: failure.host,
+          failures: 0,
+          minutes_since_last: minutes_since,
+        });
+      summary.failures += 1;
+      summary.minutes_since_last = summary.minutes_since_last.min(minutes_since);
+    }
+
+    Ok((manual_bans, hosts.into_values().collect()))
+  }
+
+  pub async fn show_ban(&self, host: IpAddr) -> Result<BanReport>
+  {
+    let cutoff = i64::saturating_sub(Self::now(), self.thresholds.expiration);
+    let subnet = self.subnet(host);
+    let mut usernames: Vec<String> = BanTracker::find()
+      .filter(ban_tracker::Column::Host.eq(host.to_string()))
+      .filter(ban_tracker::Column::FailureTimestamp.gte(cutoff))
+      .all(&self.db)
+      .await?
+      .into_iter()
+      .filter_map(|failure| failure.username)
+      .collect();
+    usernames.sort();
+    usernames.dedup();
+
+    Ok(BanReport {
+      failures: self
+        .failure_count(ban_tracker::Column::Host, &host.to_string())
+        .await?,
+      subnet_failures: self
+        .failure_count(ban_tracker::Column::Subnet, &subnet)
+        .await?,
+      subnet,
+      usernames,
+      manual_bans: Self::covering_bans(self.active_manual_bans().await?, host)
+        .into_iter()
+        .map(Self::summarize_manual_ban)
+        .collect(),
+      captcha: self
+        .exceeds(
+          host,
+          self.thresholds.captcha,
+          self.thresholds.subnet_captcha,
+        )
+        .await?,
+      fake_login: self
+        .exceeds(
+          host,
+          self.thresholds.fake_login,
+          self.thresholds.subnet_fake_login,
+        )
+        .await?,
+    })
+  }
+
   #[instrument(skip(self))]
-  async fn failure_count(&self, host: &str) -> Result<u64, DbErr>
+  async fn failure_count(&self, column: ban_tracker::Column, key: &str) -> Result<u64, DbErr>
   {
     let cutoff = i64::saturating_sub(Self::now(), self.thresholds.expiration);
     let failures = BanTracker::find()
-      .filter(ban_tracker::Column::Host.eq(host))
+      .filter(column.eq(key))
       .filter(ban_tracker::Column::FailureTimestamp.gte(cutoff))
       .count(&self.db)
       .await;
@@ -0,0 +1,258 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  sync::Arc,
+};
+
+use axum::{
+  async_trait,
+  extract::{connect_info::Connected, ConnectInfo, FromRequestParts},
+  http::{
+    header::{FORWARDED, USER_AGENT},
+    request::Parts,
+    HeaderMap, HeaderName, StatusCode,
+  },
+};
+use color_eyre::eyre::{Context, Result};
+use ipnet::IpNet;
+use tokio::net::UnixStream;
+use tracing::{event, instrument};
+
+use crate::{config::ClientAddressHeader, ip_lists::parse_network};
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// The other end of the socket a request arrived on
+#[derive(Clone, Copy, Debug)]
+pub enum PeerAddr
+{
+  Tcp(SocketAddr),
+  Unix,
+}
+
+// axum_server hands over the peer address of TCP and TLS connections directly
+impl Connected<SocketAddr> for PeerAddr
+{
+  fn connect_info(target: SocketAddr) -> Self
+  {
+    Self::Tcp(target)
+  }
+}
+
+impl<'a> Connected<&'a UnixStream> for PeerAddr
+{
+  fn connect_info(_target: &'a UnixStream) -> Self
+  {
+    Self::Unix
+  }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr
+{
+  match ip
+  {
+    IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+    IpAddr::V4(_) => ip,
+  }
+}
+
+/// Parses a node from X-Forwarded-For or the for= parameter of Forwarded, which may be
+/// quoted, bracketed and carry a port
+fn parse_node(node: &str) -> Option<IpAddr>
+{
+  let node = node.trim().trim_matches('"');
+  let ip = match node.strip_prefix('[')
+  {
+    Some(bracketed) => bracketed.split(']').next()?.parse().ok(),
+    None => node
+      .parse::<IpAddr>()
+      .ok()
+      .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip())),
+  };
+  ip.map(canonical)
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str>
+{
+  headers
+    .get_all(name)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+}
+
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>>
+{
+  header_values(headers, &FORWARDED)
+    .map(|element| {
+      element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key
+          .trim()
+          .eq_ignore_ascii_case("for")
+          .then(|| parse_node(value))
+          .flatten()
+      })
+    })
+    .collect()
+}
+
+fn x_forwarded_for_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>>
+{
+  header_values(headers, &X_FORWARDED_FOR)
+    .map(parse_node)
+    .collect()
+}
+
+#[derive(Clone)]
+pub struct TrustedProxies
+{
+  proxies: Arc<Vec<IpNet>>,
+  header: ClientAddressHeader,
+}
+
+impl TrustedProxies
+{
+  pub fn new(proxies: &[String], header: ClientAddressHeader) -> Result<Self>
+  {
+    proxies
+      .iter()
+      .map(|proxy| parse_network(proxy).wrap_err("invalid trusted proxy"))
+      .collect::<Result<Vec<_>>>()
+      .map(|proxies| Self {
+        proxies: Arc::new(proxies),
+        header,
+      })
+  }
+
+  fn contains(&self, ip: IpAddr) -> bool
+  {
+    self.proxies.iter().any(|net| net.contains(&ip))
+  }
+
+  /// Finds the client address, only believing the configured header and only from trusted
+  /// proxies
+  #[instrument(skip(self, headers))]
+  pub fn resolve(&self, peer: PeerAddr, headers: &HeaderMap) -> IpAddr
+  {
+    // only processes on this machine can connect over the unix socket
+    let (peer_ip, trusted) = match peer
+    {
+      PeerAddr::Tcp(addr) =>
+      {
+        let ip = canonical(addr.ip());
+        (ip, self.contains(ip))
+      }
+      PeerAddr::Unix => (IpAddr::V4(Ipv4Addr::LOCALHOST), true),
+    };
+    if !trusted
+    {
+      return peer_ip;
+    }
+
+    let chain = match self.header
+    {
+      ClientAddressHeader::Forwarded => forwarded_chain(headers),
+      ClientAddressHeader::XForwardedFor => x_forwarded_for_chain(headers),
+      ClientAddressHeader::XRealIp =>
+      {
+        return headers
+          .get(&X_REAL_IP)
+          .and_then(|value| value.to_str().ok())
+          .and_then(parse_node)
+          .unwrap_or(peer_ip)
+      }
+    };
+
+    // walk back from the nearest hop, stopping at the first address we don't trust since
+    // everything to its left was supplied by the client
+    let mut client = peer_ip;
+    for hop in chain.into_iter().rev()
+    {
+      match hop
+      {
+        Some(ip) =>
+        {
+          client = ip;
+          if !self.contains(ip)
+          {
+            break;
+          }
+        }
+        None =>
+        {
+          event!(tracing::Level::DEBUG, "unparseable forwarding hop");
+          break;
+        }
+      }
+    }
+    client
+  }
+}
+
+/// Address of the client a request came from
+#[derive(Debug)]
+pub struct ClientAddr(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr
+{
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection>
+  {
+    let proxies = parts
+      .extensions
+      .get::<TrustedProxies>()
+      .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let ConnectInfo(peer) = parts
+      .extensions
+      .get::<ConnectInfo<PeerAddr>>()
+      .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Self(proxies.resolve(*peer, &parts.headers)))
+  }
+}
+
+/// The client a request came from along with the browser it claims to be, which a session is
+/// compared against to see whether it's still being used from where it logged in
+#[derive(Debug)]
+pub struct Client
+{
+  pub ip: IpAddr,
+  pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Client
+{
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection>
+  {
+    let ClientAddr(ip) = ClientAddr::from_request_parts(parts, state).await?;
+    let user_agent = parts
+      .headers
+      .get(USER_AGENT)
+      .and_then(|value| value.to_str().ok())
+      .map(str::to_owned);
+    Ok(Self { ip, user_agent })
+  }
+}
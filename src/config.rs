@@ -24,11 +24,337 @@ use std::{
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum DenyAction
+{
+  #[default]
+  FakeLogin,
+  Forbidden,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum ChallengeKind
+{
+  #[default]
+  Captcha,
+  ProofOfWork,
+}
+
+/// The header the trusted proxies put the client address in.  Only this one is read, since
+/// proxies pass the others along as the client sent them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum ClientAddressHeader
+{
+  Forwarded,
+  #[default]
+  XForwardedFor,
+  XRealIp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BehaviourSettings
 {
   pub captcha: Option<u64>,
   pub fake_login: Option<u64>,
   pub expiration: i64,
+  pub subnet_captcha: Option<u64>,
+  pub subnet_fake_login: Option<u64>,
+  #[serde(default = "default_ipv4_prefix")]
+  pub ipv4_prefix: u8,
+  #[serde(default = "default_ipv6_prefix")]
+  pub ipv6_prefix: u8,
+  pub account_lockout: Option<u64>,
+  #[serde(default = "default_account_backoff")]
+  pub account_backoff: i64,
+  #[serde(default = "default_account_backoff_max")]
+  pub account_backoff_max: i64,
+  #[serde(default)]
+  pub allow: Vec<String>,
+  #[serde(default)]
+  pub deny: Vec<String>,
+  pub ip_list_file: Option<PathBuf>,
+  #[serde(default)]
+  pub deny_action: DenyAction,
+  #[serde(default)]
+  pub challenge: ChallengeKind,
+  #[serde(default = "default_pow_difficulty")]
+  pub pow_difficulty: u32,
+  #[serde(default = "default_pow_difficulty_max")]
+  pub pow_difficulty_max: u32,
+}
+
+fn default_ipv4_prefix() -> u8
+{
+  32
+}
+
+fn default_ipv6_prefix() -> u8
+{
+  64
+}
+
+fn default_account_backoff() -> i64
+{
+  1
+}
+
+fn default_account_backoff_max() -> i64
+{
+  60
+}
+
+fn default_pow_difficulty() -> u32
+{
+  16
+}
+
+fn default_pow_difficulty_max() -> u32
+{
+  22
+}
+
+impl Default for BehaviourSettings
+{
+  fn default() -> Self
+  {
+    Self {
+      captcha: None,
+      fake_login: None,
+      expiration: 0,
+      subnet_captcha: None,
+      subnet_fake_login: None,
+      ipv4_prefix: default_ipv4_prefix(),
+      ipv6_prefix: default_ipv6_prefix(),
+      account_lockout: None,
+      account_backoff: default_account_backoff(),
+      account_backoff_max: default_account_backoff_max(),
+      allow: Vec::new(),
+      deny: Vec::new(),
+      ip_list_file: None,
+      deny_action: DenyAction::FakeLogin,
+      challenge: ChallengeKind::Captcha,
+      pow_difficulty: default_pow_difficulty(),
+      pow_difficulty_max: default_pow_difficulty_max(),
+    }
+  }
+}
+
+/// Distortions applied to the captcha image, in order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum CaptchaFilter
+{
+  Noise
+  {
+    probability: f64
+  },
+  Grid
+  {
+    x_gap: u32, y_gap: u32
+  },
+  Wave
+  {
+    frequency: f64,
+    amplitude: f64,
+    #[serde(default)]
+    vertical: bool,
+  },
+  Dots
+  {
+    count: u32,
+    min_radius: u32,
+    max_radius: u32,
+  },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CaptchaSettings
+{
+  pub min_length: u32,
+  pub max_length: u32,
+  pub width: u32,
+  pub height: u32,
+  pub case_sensitive: bool,
+  /// Characters to draw from.  Unset uses the captcha library's own set
+  pub charset: Option<String>,
+  /// Offer a spoken version of the captcha.  Needs a charset with a recording for every
+  /// character and case_sensitive turned off
+  pub audio: bool,
+  pub filters: Vec<CaptchaFilter>,
+}
+
+impl Default for CaptchaSettings
+{
+  fn default() -> Self
+  {
+    Self {
+      min_length: 4,
+      max_length: 6,
+      width: 220,
+      height: 120,
+      case_sensitive: true,
+      charset: None,
+      audio: false,
+      filters: vec![
+        CaptchaFilter::Noise { probability: 0.3 },
+        CaptchaFilter::Grid { x_gap: 6, y_gap: 6 },
+        CaptchaFilter::Wave {
+          frequency: 2.0,
+          amplitude: 10.0,
+          vertical: false,
+        },
+        CaptchaFilter::Dots {
+          count: 15,
+          min_radius: 4,
+          max_radius: 7,
+        },
+      ],
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum TotpAlgorithm
+{
+  #[default]
+  Sha1,
+  Sha256,
+  Sha512,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct MfaSettings
+{
+  pub totp_skew: u64,
+  pub totp_algorithm: TotpAlgorithm,
+  pub totp_digits: u32,
+  pub totp_period: u64,
+  pub totp_secret_bytes: usize,
+  /// Offers to remember a browser, skipping the one time password on it for this many days
+  pub remember_device_days: Option<u32>,
+}
+
+impl Default for MfaSettings
+{
+  fn default() -> Self
+  {
+    Self {
+      totp_skew: 1,
+      totp_algorithm: TotpAlgorithm::Sha1,
+      totp_digits: 6,
+      totp_period: 30,
+      totp_secret_bytes: 20,
+      remember_device_days: None,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LdapSettings
+{
+  pub url: String,
+  pub bind_dn: String,
+  #[serde(default)]
+  pub starttls: bool,
+  pub group_filter: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type")]
+pub enum PasswordBackend
+{
+  #[default]
+  Local,
+  Ldap(LdapSettings),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OidcSettings
+{
+  pub issuer: String,
+  pub token_lifetime_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum UpstreamClaim
+{
+  #[default]
+  Subject,
+  PreferredUsername,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpstreamSettings
+{
+  pub name: String,
+  pub issuer: String,
+  pub client_id: String,
+  pub client_secret: Option<String>,
+  pub redirect_url: String,
+  #[serde(default)]
+  pub username_claim: UpstreamClaim,
+  #[serde(default)]
+  pub auto_provision: bool,
+  #[serde(default = "default_upstream_scopes")]
+  pub scopes: Vec<String>,
+}
+
+fn default_upstream_scopes() -> Vec<String>
+{
+  vec![String::from("profile")]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HeaderSettings
+{
+  pub user: String,
+  pub groups: String,
+  pub email: String,
+}
+
+impl Default for HeaderSettings
+{
+  fn default() -> Self
+  {
+    Self {
+      user: String::from("X-Remote-User"),
+      groups: String::from("X-Remote-Groups"),
+      email: String::from("X-Remote-Email"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum AccessDefault
+{
+  #[default]
+  Allow,
+  Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessRule
+{
+  pub host: String,
+  #[serde(default = "default_rule_path")]
+  pub path: String,
+  #[serde(default)]
+  pub groups: Vec<String>,
+}
+
+fn default_rule_path() -> String
+{
+  String::from("/")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccessSettings
+{
+  pub default: AccessDefault,
+  pub rules: Vec<AccessRule>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -39,12 +365,28 @@ pub enum SessionStorage
   Redis(String),
 }
 
+/// What a session is tied to from the login it started with.  Requests that don't match are
+/// turned away, so a stolen cookie is no use elsewhere
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct SessionBinding
+{
+  pub ipv4_prefix: Option<u8>,
+  pub ipv6_prefix: Option<u8>,
+  pub user_agent: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionSettings
 {
   pub session_timeout_seconds: Option<u64>,
+  /// Log users out this long after they logged in, however active they've been
+  #[serde(default)]
+  pub max_session_lifetime_seconds: Option<u64>,
   pub cookie_name: Option<String>,
   pub backend: SessionStorage,
+  #[serde(default)]
+  pub bind: SessionBinding,
 }
 
 impl Default for SessionSettings
@@ -54,7 +396,9 @@ impl Default for SessionSettings
     Self {
       backend: SessionStorage::InMemory,
       session_timeout_seconds: None,
+      max_session_lifetime_seconds: None,
       cookie_name: None,
+      bind: Default::default(),
     }
   }
 }
@@ -120,13 +464,44 @@ impl Default for BindTo
   }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HostSettings
 {
   pub cluster_secret: String,
   pub database_url: String,
   pub domain: String,
   pub bind: BindTo,
+  pub webauthn_origin: Option<String>,
+  #[serde(default)]
+  pub redirect_hosts: Vec<String>,
+  pub landing_page: Option<String>,
+  #[serde(default = "default_trusted_proxies")]
+  pub trusted_proxies: Vec<String>,
+  #[serde(default)]
+  pub client_address_header: ClientAddressHeader,
+}
+
+fn default_trusted_proxies() -> Vec<String>
+{
+  vec![String::from("127.0.0.1/32"), String::from("::1/128")]
+}
+
+impl Default for HostSettings
+{
+  fn default() -> Self
+  {
+    Self {
+      cluster_secret: Default::default(),
+      database_url: Default::default(),
+      domain: Default::default(),
+      bind: Default::default(),
+      webauthn_origin: None,
+      redirect_hosts: Vec::new(),
+      landing_page: None,
+      trusted_proxies: default_trusted_proxies(),
+      client_address_header: Default::default(),
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -134,6 +509,18 @@ pub struct Settings
 {
   pub host: HostSettings,
   pub behaviour: BehaviourSettings,
+  #[serde(default)]
+  pub captcha: CaptchaSettings,
+  #[serde(default)]
+  pub mfa: MfaSettings,
+  #[serde(default)]
+  pub password: PasswordBackend,
+  pub oidc: Option<OidcSettings>,
+  pub upstream: Option<UpstreamSettings>,
+  #[serde(default)]
+  pub headers: HeaderSettings,
+  #[serde(default)]
+  pub access: AccessSettings,
   pub session: SessionSettings,
   pub logging: Option<Logging>,
 }
@@ -145,6 +532,13 @@ impl Default for Settings
     Self {
       host: Default::default(),
       behaviour: Default::default(),
+      captcha: Default::default(),
+      mfa: Default::default(),
+      password: Default::default(),
+      oidc: None,
+      upstream: None,
+      headers: Default::default(),
+      access: Default::default(),
       session: Default::default(),
       logging: Some(Default::default()),
     }
@@ -19,25 +19,51 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 use crate::entities::prelude::*;
 use color_eyre::eyre::{eyre, Context, Result};
 use sea_orm::{
-  ConnectionTrait, DatabaseConnection, DbBackend, DbConn, EntityTrait, ExecResult, Schema,
+  sea_query::{Query, Table},
+  ConnectionTrait, DatabaseConnection, DbBackend, DbConn, EntityTrait, Iterable, Schema,
   SqlxMySqlConnector, SqlxPostgresConnector, SqlxSqliteConnector,
 };
 use sqlx::{MySql, Pool, Postgres, Sqlite};
 
-async fn create_table<E: EntityTrait>(db: &DbConn, entity: E)
-  -> Result<ExecResult, sea_orm::DbErr>
+async fn create_table<E: EntityTrait>(db: &DbConn, entity: E) -> Result<(), sea_orm::DbErr>
 {
   let builder = db.get_database_backend();
   let schema = Schema::new(builder);
   let stmt = builder.build(schema.create_table_from_entity(entity).if_not_exists());
+  db.execute(stmt).await?;
 
-  db.execute(stmt).await
+  // tables created by older versions may be missing newer columns
+  for column in E::Column::iter()
+  {
+    let probe = builder.build(Query::select().column(column).from(entity).limit(0));
+    if db.query_one(probe).await.is_err()
+    {
+      let stmt = builder.build(
+        Table::alter()
+          .table(entity)
+          .add_column(&mut schema.get_column_def::<E>(column)),
+      );
+      db.execute(stmt).await?;
+    }
+  }
+  Ok(())
 }
 
 async fn create_tables(db: &DatabaseConnection) -> Result<(), sea_orm::DbErr>
 {
   create_table(db, User).await?;
   create_table(db, BanTracker).await?;
+  create_table(db, ManualBan).await?;
+  create_table(db, SecurityKey).await?;
+  create_table(db, RecoveryCode).await?;
+  create_table(db, OidcClient).await?;
+  create_table(db, OidcAuthorizationCode).await?;
+  create_table(db, SigningKey).await?;
+  create_table(db, Group).await?;
+  create_table(db, GroupMember).await?;
+  create_table(db, Invite).await?;
+  create_table(db, UserSession).await?;
+  create_table(db, TrustedDevice).await?;
   Ok(())
 }
 
@@ -30,6 +30,8 @@ pub struct Model
   pub id: i64,
   pub host: String,
   pub failure_timestamp: i64,
+  pub username: Option<String>,
+  pub subnet: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
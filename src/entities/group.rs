@@ -0,0 +1,60 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use sea_orm::{
+  ActiveModelBehavior, DeriveEntityModel, DerivePrimaryKey, DeriveRelation, EntityTrait, EnumIter,
+  PrimaryKeyTrait, Related, RelationDef, RelationTrait,
+};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "group")]
+pub struct Model
+{
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub name: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation
+{
+  #[sea_orm(has_many = "super::group_member::Entity")]
+  GroupMember,
+}
+
+impl Related<super::group_member::Entity> for Entity
+{
+  fn to() -> RelationDef
+  {
+    Relation::GroupMember.def()
+  }
+}
+
+impl Related<super::user::Entity> for Entity
+{
+  fn to() -> RelationDef
+  {
+    super::group_member::Relation::User.def()
+  }
+
+  fn via() -> Option<RelationDef>
+  {
+    Some(super::group_member::Relation::Group.def().rev())
+  }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
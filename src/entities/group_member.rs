@@ -0,0 +1,69 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use sea_orm::{
+  ActiveModelBehavior, DeriveEntityModel, DerivePrimaryKey, DeriveRelation, EntityTrait, EnumIter,
+  PrimaryKeyTrait, Related, RelationDef, RelationTrait,
+};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "group_member")]
+pub struct Model
+{
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub username: String,
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub group_name: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation
+{
+  #[sea_orm(
+    belongs_to = "super::user::Entity",
+    from = "Column::Username",
+    to = "super::user::Column::Username",
+    on_delete = "Cascade"
+  )]
+  User,
+  #[sea_orm(
+    belongs_to = "super::group::Entity",
+    from = "Column::GroupName",
+    to = "super::group::Column::Name",
+    on_delete = "Cascade"
+  )]
+  Group,
+}
+
+impl Related<super::user::Entity> for Entity
+{
+  fn to() -> RelationDef
+  {
+    Relation::User.def()
+  }
+}
+
+impl Related<super::group::Entity> for Entity
+{
+  fn to() -> RelationDef
+  {
+    Relation::Group.def()
+  }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -17,5 +17,16 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
 pub mod ban_tracker;
+pub mod group;
+pub mod group_member;
+pub mod invite;
+pub mod manual_ban;
+pub mod oidc_authorization_code;
+pub mod oidc_client;
 pub mod prelude;
+pub mod recovery_code;
+pub mod security_key;
+pub mod signing_key;
+pub mod trusted_device;
 pub mod user;
+pub mod user_session;
@@ -16,4 +16,11 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-pub use super::{ban_tracker::Entity as BanTracker, user::Entity as User};
+pub use super::{
+  ban_tracker::Entity as BanTracker, group::Entity as Group, group_member::Entity as GroupMember,
+  invite::Entity as Invite, manual_ban::Entity as ManualBan,
+  oidc_authorization_code::Entity as OidcAuthorizationCode, oidc_client::Entity as OidcClient,
+  recovery_code::Entity as RecoveryCode, security_key::Entity as SecurityKey,
+  signing_key::Entity as SigningKey, trusted_device::Entity as TrustedDevice, user::Entity as User,
+  user_session::Entity as UserSession,
+};
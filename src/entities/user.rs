@@ -17,8 +17,8 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
 use sea_orm::{
-  ActiveModelBehavior, DeriveEntityModel, DerivePrimaryKey, EntityTrait, EnumIter, PrimaryKeyTrait,
-  RelationDef, RelationTrait,
+  ActiveModelBehavior, DeriveEntityModel, DerivePrimaryKey, DeriveRelation, EntityTrait, EnumIter,
+  PrimaryKeyTrait, Related, RelationDef, RelationTrait,
 };
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
@@ -29,16 +29,46 @@ pub struct Model
   pub username: String,
   pub password_hash: String,
   pub totp_secret: Vec<u8>,
+  pub last_totp_step: Option<i64>,
+  pub totp_algorithm: Option<String>,
+  pub totp_digits: Option<i32>,
+  pub totp_period: Option<i64>,
+  pub email: Option<String>,
+  pub session_epoch: Option<i64>,
+  /// A TOTP secret that has been shown to the user but not yet confirmed with a valid code
+  pub pending_totp_secret: Option<Vec<u8>>,
+  /// The parameters the pending secret was shown with, so changing the settings in between
+  /// doesn't invalidate it
+  pub pending_totp_algorithm: Option<String>,
+  pub pending_totp_digits: Option<i32>,
+  pub pending_totp_period: Option<i64>,
 }
 
-#[derive(Copy, Clone, Debug, EnumIter)]
-pub enum Relation {}
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation
+{
+  #[sea_orm(has_many = "super::group_member::Entity")]
+  GroupMember,
+}
+
+impl Related<super::group_member::Entity> for Entity
+{
+  fn to() -> RelationDef
+  {
+    Relation::GroupMember.def()
+  }
+}
 
-impl RelationTrait for Relation
+impl Related<super::group::Entity> for Entity
 {
-  fn def(&self) -> RelationDef
+  fn to() -> RelationDef
+  {
+    super::group_member::Relation::Group.def()
+  }
+
+  fn via() -> Option<RelationDef>
   {
-    panic!("No RelationDef")
+    Some(super::group_member::Relation::User.def().rev())
   }
 }
 
@@ -22,7 +22,8 @@ use color_eyre::{
   Report,
 };
 use config::Config;
-use std::fs::OpenOptions;
+use ipnet::IpNet;
+use std::{fs::OpenOptions, net::IpAddr};
 use tracing::{metadata::LevelFilter, Subscriber};
 use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
 use tracing_log::{AsTrace, LogTracer};
@@ -37,8 +38,13 @@ use tracing_subscriber::{
   registry::LookupSpan,
 };
 
-use crate::config::{
-  BehaviourSettings, HostSettings, LogLevel, Logging, SessionSettings, Settings,
+use crate::{
+  config::{
+    AccessSettings, BehaviourSettings, CaptchaSettings, HeaderSettings, HostSettings, LogLevel,
+    Logging, MfaSettings, OidcSettings, PasswordBackend, SessionSettings, Settings,
+    UpstreamSettings,
+  },
+  ip_lists,
 };
 
 #[derive(Parser)]
@@ -150,12 +156,60 @@ pub enum Command
   Run,
   /// Add a user to the configured database
   AddUser(ShowsQrCode),
+  /// Create an account and print a link the user follows to set their own password and TOTP
+  InviteUser(InvitesUser),
+  /// List outstanding invites
+  ListInvites,
+  /// Invalidate a user's outstanding invite links
+  RevokeInvite(RequiresUsername),
   /// Delete a user from the configured database
   DeleteUser(RequiresUsername),
   /// Reset the password for a user
   ResetPassword(RequiresUsername),
+  /// List the sessions a user is logged in with
+  ListSessions(RequiresUsername),
+  /// Log a user out of one session, or all of them
+  RevokeSessions(RevokesSessions),
+  /// List the devices a user has asked to be remembered
+  ListDevices(RequiresUsername),
+  /// Forget one of a user's remembered devices, or all of them
+  RevokeDevices(RevokesDevices),
+  /// Set or clear the email address passed to upstream applications
+  SetEmail(SetsEmail),
   /// Generate a new TOTP secret for a user
   ResetMFA(ShowsQrCode),
+  /// Replace the recovery codes for a user, invalidating the old set
+  RegenerateRecoveryCodes(RequiresUsername),
+  /// Create a group that access rules can refer to
+  AddGroup(NamesGroup),
+  /// Delete a group and all of its memberships
+  DeleteGroup(NamesGroup),
+  /// List groups and their members
+  ListGroups,
+  /// Add a user to a group
+  AddToGroup(GroupMembership),
+  /// Remove a user from a group
+  RemoveFromGroup(GroupMembership),
+  /// Check whether the configured access rules let a user reach a URL
+  CheckAccess(ChecksAccess),
+  /// List manual bans and addresses with recent failed logins
+  ListBans,
+  /// Show failed logins and bans affecting an address
+  ShowBan(RequiresAddress),
+  /// Lift a manual ban and forget recent failures for an address or network
+  Unban(RequiresNetwork),
+  /// Ban an address or network across the cluster
+  Ban(BansNetwork),
+  /// List the security keys registered to a user
+  ListSecurityKeys(RequiresUsername),
+  /// Remove a registered security key from a user
+  RemoveSecurityKey(NamesSecurityKey),
+  /// Register an application that may sign users in through OpenID Connect
+  AddOidcClient(DescribesOidcClient),
+  /// Remove a registered OpenID Connect application
+  DeleteOidcClient(RequiresClientId),
+  /// List the registered OpenID Connect applications
+  ListOidcClients,
 }
 
 #[derive(Args)]
@@ -168,6 +222,32 @@ pub struct ShowsQrCode
   /// If specified, display TOTP URL as a scannable QR code
   #[clap(short, long, value_parser, default_value_t = false)]
   pub show_qr_code: bool,
+
+  /// Instead of displaying a TOTP secret, have the user set one up on their next login
+  #[clap(
+    short,
+    long,
+    value_parser,
+    default_value_t = false,
+    conflicts_with = "show_qr_code"
+  )]
+  pub enroll_on_login: bool,
+}
+
+#[derive(Args)]
+pub struct InvitesUser
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// How long the link works for, e.g. 90m, 12h or 7d
+  #[clap(short, long, value_parser = parse_duration, default_value = "3d")]
+  pub expires: i64,
+
+  /// Address of the authentication pages the link points to.  Defaults to webauthn_origin
+  #[clap(short, long, value_parser)]
+  pub base_url: Option<String>,
 }
 
 #[derive(Args)]
@@ -178,10 +258,172 @@ pub struct RequiresUsername
   pub username: String,
 }
 
+#[derive(Args)]
+pub struct RevokesSessions
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Session to revoke, as shown by list-sessions.  Revokes every session if omitted
+  #[clap(short, long, value_parser)]
+  pub id: Option<String>,
+}
+
+#[derive(Args)]
+pub struct RevokesDevices
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Device to forget, as shown by list-devices.  Forgets every device if omitted
+  #[clap(short, long, value_parser)]
+  pub id: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SetsEmail
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Email address, omit to clear
+  #[clap(short, long, value_parser)]
+  pub email: Option<String>,
+}
+
+#[derive(Args)]
+pub struct NamesGroup
+{
+  /// Target group
+  #[clap(short, long, value_parser)]
+  pub group: String,
+}
+
+#[derive(Args)]
+pub struct GroupMembership
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Target group
+  #[clap(short, long, value_parser)]
+  pub group: String,
+}
+
+#[derive(Args)]
+pub struct ChecksAccess
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Full URL of the protected resource
+  #[clap(long, value_parser)]
+  pub url: String,
+}
+
+#[derive(Args)]
+pub struct RequiresAddress
+{
+  /// Client address
+  #[clap(long, value_parser)]
+  pub host: IpAddr,
+}
+
+#[derive(Args)]
+pub struct RequiresNetwork
+{
+  /// Client address or network in CIDR notation
+  #[clap(long, value_parser = parse_network)]
+  pub host: IpNet,
+}
+
+#[derive(Args)]
+pub struct BansNetwork
+{
+  /// Client address or network in CIDR notation
+  #[clap(long, value_parser = parse_network)]
+  pub host: IpNet,
+
+  /// How long the ban lasts, e.g. 90m, 12h or 7d.  Bans are permanent if omitted
+  #[clap(short, long, value_parser = parse_duration)]
+  pub duration: Option<i64>,
+}
+
+fn parse_network(text: &str) -> Result<IpNet, String>
+{
+  ip_lists::parse_network(text).map_err(|error| error.to_string())
+}
+
+/// Parses a duration into minutes, which is the granularity bans and invites are tracked at
+fn parse_duration(text: &str) -> Result<i64, String>
+{
+  let (number, minutes) = match text.char_indices().last()
+  {
+    Some((index, 'm')) => (&text[..index], 1),
+    Some((index, 'h')) => (&text[..index], 60),
+    Some((index, 'd')) => (&text[..index], 60 * 24),
+    _ => (text, 1),
+  };
+  number
+    .parse::<i64>()
+    .ok()
+    .filter(|number| *number > 0)
+    .and_then(|number| number.checked_mul(minutes))
+    .ok_or_else(|| format!("{} is not a duration like 90m, 12h or 7d", text))
+}
+
+#[derive(Args)]
+pub struct NamesSecurityKey
+{
+  /// Target username
+  #[clap(short, long, value_parser)]
+  pub username: String,
+
+  /// Name the security key was registered with
+  #[clap(short, long, value_parser)]
+  pub name: String,
+}
+
+#[derive(Args)]
+pub struct DescribesOidcClient
+{
+  /// Client identifier the application will present
+  #[clap(short, long, value_parser)]
+  pub client_id: String,
+
+  /// Allowed redirect URI, may be given more than once
+  #[clap(short, long, value_parser, required = true)]
+  pub redirect_uri: Vec<String>,
+
+  /// If specified, no client secret is issued and PKCE is required
+  #[clap(short, long, value_parser, default_value_t = false)]
+  pub public: bool,
+}
+
+#[derive(Args)]
+pub struct RequiresClientId
+{
+  /// Client identifier of the application
+  #[clap(short, long, value_parser)]
+  pub client_id: String,
+}
+
 pub fn parse_env() -> Result<(
   SessionSettings,
   HostSettings,
   BehaviourSettings,
+  CaptchaSettings,
+  MfaSettings,
+  PasswordBackend,
+  Option<OidcSettings>,
+  Option<UpstreamSettings>,
+  HeaderSettings,
+  AccessSettings,
   Command,
   Vec<WorkerGuard>,
 )>
@@ -221,6 +463,13 @@ pub fn parse_env() -> Result<(
     settings.session,
     settings.host,
     settings.behaviour,
+    settings.captcha,
+    settings.mfa,
+    settings.password,
+    settings.oidc,
+    settings.upstream,
+    settings.headers,
+    settings.access,
     args.command,
     guards,
   ))
@@ -0,0 +1,200 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+  net::IpAddr,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use color_eyre::eyre::{eyre, Context, Result};
+use ipnet::IpNet;
+use tokio::{
+  signal::unix::{signal, SignalKind},
+  task::{self, JoinHandle},
+};
+use tracing::{event, instrument};
+
+use crate::config::BehaviourSettings;
+
+/// Parses a network in CIDR notation or a single address
+pub fn parse_network(text: &str) -> Result<IpNet>
+{
+  text
+    .parse::<IpNet>()
+    .or_else(|_| text.parse::<IpAddr>().map(IpNet::from))
+    .map_err(|_| eyre!("{} is not an address or network", text))
+}
+
+#[derive(Default)]
+struct Networks
+{
+  allow: Vec<IpNet>,
+  deny: Vec<IpNet>,
+}
+
+impl Networks
+{
+  fn load(allow: &[String], deny: &[String], file: Option<&Path>) -> Result<Self>
+  {
+    let mut networks = Self {
+      allow: allow
+        .iter()
+        .map(|network| parse_network(network))
+        .collect::<Result<_>>()?,
+      deny: deny
+        .iter()
+        .map(|network| parse_network(network))
+        .collect::<Result<_>>()?,
+    };
+
+    if let Some(file) = file
+    {
+      let contents = std::fs::read_to_string(file)
+        .wrap_err_with(|| format!("failed to read {}", file.display()))?;
+      for (number, line) in contents.lines().enumerate()
+      {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty()
+        {
+          continue;
+        }
+        match line.split_once(char::is_whitespace)
+        {
+          Some(("allow", network)) => networks.allow.push(parse_network(network.trim())?),
+          Some(("deny", network)) => networks.deny.push(parse_network(network.trim())?),
+          _ =>
+          {
+            return Err(eyre!(
+              "{}:{}: expected allow or deny followed by a network",
+              file.display(),
+              number + 1
+            ))
+          }
+        }
+      }
+    }
+
+    Ok(networks)
+  }
+}
+
+/// Networks that are always or never trusted, shared between clones so a reload is seen
+/// everywhere
+#[derive(Clone)]
+pub struct IpLists
+{
+  allow: Vec<String>,
+  deny: Vec<String>,
+  file: Option<PathBuf>,
+  networks: Arc<RwLock<Networks>>,
+}
+
+impl IpLists
+{
+  pub fn new(settings: &BehaviourSettings) -> Result<Self>
+  {
+    let networks = Networks::load(
+      &settings.allow,
+      &settings.deny,
+      settings.ip_list_file.as_deref(),
+    )?;
+    Ok(Self {
+      allow: settings.allow.clone(),
+      deny: settings.deny.clone(),
+      file: settings.ip_list_file.clone(),
+      networks: Arc::new(RwLock::new(networks)),
+    })
+  }
+
+  fn read(&self) -> std::sync::RwLockReadGuard<'_, Networks>
+  {
+    // the lists are only ever replaced whole, so a poisoned lock still holds a valid list
+    self
+      .networks
+      .read()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Denied networks take precedence over allowed ones
+  pub fn denied(&self, host: IpAddr) -> bool
+  {
+    self
+      .read()
+      .deny
+      .iter()
+      .any(|network| network.contains(&host))
+  }
+
+  pub fn allowed(&self, host: IpAddr) -> bool
+  {
+    !self.denied(host)
+      && self
+        .read()
+        .allow
+        .iter()
+        .any(|network| network.contains(&host))
+  }
+
+  #[instrument(skip(self))]
+  fn reload(&self)
+  {
+    match Networks::load(&self.allow, &self.deny, self.file.as_deref())
+    {
+      Ok(networks) =>
+      {
+        event!(
+          tracing::Level::INFO,
+          "loaded {} allowed and {} denied networks",
+          networks.allow.len(),
+          networks.deny.len()
+        );
+        *self
+          .networks
+          .write()
+          .unwrap_or_else(|poisoned| poisoned.into_inner()) = networks;
+      }
+      Err(error) => event!(
+        tracing::Level::ERROR,
+        "keeping previous ip lists: {}",
+        error
+      ),
+    }
+  }
+
+  /// Rereads the list file whenever the process receives SIGHUP
+  pub fn reload_task(&self) -> JoinHandle<()>
+  {
+    let lists = self.clone();
+    task::spawn(async move {
+      let mut hangups = match signal(SignalKind::hangup())
+      {
+        Ok(hangups) => hangups,
+        Err(error) =>
+        {
+          event!(tracing::Level::ERROR, "{}", error);
+          return;
+        }
+      };
+      while hangups.recv().await.is_some()
+      {
+        lists.reload();
+      }
+    })
+  }
+}
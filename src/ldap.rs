@@ -0,0 +1,71 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use color_eyre::eyre::{Context, Result};
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope};
+use tracing::{event, instrument};
+
+use crate::config::LdapSettings;
+
+#[instrument(skip(settings, password))]
+pub async fn authenticate(settings: &LdapSettings, username: &str, password: &str) -> Result<bool>
+{
+  // an empty password is an unauthenticated bind, which most servers happily accept
+  if password.is_empty()
+  {
+    return Ok(false);
+  }
+
+  let (conn, mut ldap) = LdapConnAsync::with_settings(
+    LdapConnSettings::new().set_starttls(settings.starttls),
+    &settings.url,
+  )
+  .await
+  .wrap_err("failed to connect to LDAP server")?;
+  ldap3::drive!(conn);
+
+  let user_dn = settings.bind_dn.replace("{username}", &dn_escape(username));
+  let bound = ldap
+    .simple_bind(&user_dn, password)
+    .await?
+    .success()
+    .is_ok();
+  let authorized = match &settings.group_filter
+  {
+    Some(filter) if bound =>
+    {
+      // the filter is evaluated against the user's own entry
+      let filter = filter.replace("{username}", &ldap_escape(username));
+      let (entries, _) = ldap
+        .search(&user_dn, Scope::Base, &filter, vec!["1.1"])
+        .await?
+        .success()?;
+      !entries.is_empty()
+    }
+    _ => bound,
+  };
+  ldap.unbind().await?;
+
+  event!(
+    tracing::Level::INFO,
+    "ldap bind succeeded: {}, authorized: {}",
+    bound,
+    authorized
+  );
+  Ok(authorized)
+}
@@ -19,38 +19,75 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 #![forbid(unsafe_code)]
 #![allow(clippy::all)]
 
+mod access;
 mod challenge_manager;
+mod client_addr;
 mod config;
 mod db;
 mod entities;
 mod env_parser;
+mod ip_lists;
+mod ldap;
+mod oidc;
+mod redirect;
 mod session;
 mod tui;
+mod upstream;
 mod user_manager;
 mod web;
 
+use access::{AccessPolicy, Target};
 use challenge_manager::ChallengeManager;
-use color_eyre::eyre::{Context, Result};
+use client_addr::TrustedProxies;
+use color_eyre::eyre::{eyre, Context, Result};
 use db::connect;
 use env_parser::{parse_env, Command};
+use oidc::{ClientRegistry, OidcProvider};
+use redirect::RedirectPolicy;
 use session::SessionBackendStorage;
 use sha2::{Digest, Sha512};
-use tui::{get_password, maybe_show_qr_code};
+use tui::{get_password, maybe_show_qr_code, show_recovery_codes};
+use upstream::UpstreamProvider;
 use user_manager::UserManager;
 use web::WebServer;
 
 #[tokio::main]
 async fn main() -> Result<()>
 {
-  let (session_config, host_config, behaviour_config, command, _guards) = parse_env()?;
+  let (
+    session_config,
+    host_config,
+    behaviour_config,
+    captcha_config,
+    mfa_config,
+    password_config,
+    oidc_config,
+    upstream_config,
+    header_config,
+    access_config,
+    command,
+    _guards,
+  ) = parse_env()?;
 
   let db = connect(&host_config.database_url).await?;
 
   let mut hasher = Sha512::new();
   hasher.update(host_config.cluster_secret.as_bytes());
   let secret = hasher.finalize().to_vec();
-  let user_manager = UserManager::new(db.1.clone(), host_config.domain.clone(), secret.clone())
-    .wrap_err("failed to initialize user manager")?;
+  let user_manager = UserManager::new(
+    db.1.clone(),
+    host_config.domain.clone(),
+    secret.clone(),
+    host_config.webauthn_origin.clone(),
+    mfa_config,
+    password_config,
+  )
+  .wrap_err("failed to initialize user manager")?;
+
+  let challenge_manager =
+    ChallengeManager::<128>::new(db.1.clone(), behaviour_config, captcha_config)
+      .await
+      .wrap_err("invalid behaviour or captcha settings")?;
 
   match command
   {
@@ -58,38 +95,328 @@ async fn main() -> Result<()>
     {
       WebServer::new(
         user_manager,
-        ChallengeManager::<128>::new(db.1.clone(), behaviour_config).await,
+        challenge_manager,
+        match oidc_config
+        {
+          Some(settings) => Some(
+            OidcProvider::new(db.1.clone(), settings)
+              .await
+              .wrap_err("failed to initialize OpenID Connect provider")?,
+          ),
+          None => None,
+        },
+        match upstream_config
+        {
+          Some(settings) => Some(
+            UpstreamProvider::new(settings)
+              .await
+              .wrap_err("failed to initialize upstream identity provider")?,
+          ),
+          None => None,
+        },
+        header_config,
+        AccessPolicy::new(access_config),
+        RedirectPolicy::new(&host_config).wrap_err("invalid redirect settings")?,
+        TrustedProxies::new(
+          &host_config.trusted_proxies,
+          host_config.client_address_header,
+        )?,
         session_config.session_timeout_seconds,
+        session_config.max_session_lifetime_seconds,
+        session_config.bind,
         host_config.domain.clone(),
-      )
+      )?
       .run(
         SessionBackendStorage::from_settings(session_config, db.0, &secret, host_config.domain)?,
         host_config.bind,
       )
       .await?;
     }
-    Command::AddUser(args) => maybe_show_qr_code(
-      user_manager
-        .register(args.username, get_password()?)
+    Command::AddUser(args) =>
+    {
+      let password = if user_manager.manages_passwords()
+      {
+        Some(get_password()?)
+      }
+      else
+      {
+        None
+      };
+      if args.enroll_on_login
+      {
+        user_manager
+          .register_pending(args.username, password)
+          .await
+          .wrap_err("failed to create new user")?;
+        println!("The user will be asked to set up a one time password when they first log in");
+      }
+      else
+      {
+        let (setup_code, recovery_codes) = user_manager
+          .register(args.username, password)
+          .await
+          .wrap_err("failed to create new user")?;
+        maybe_show_qr_code(setup_code, args.show_qr_code)?;
+        show_recovery_codes(recovery_codes);
+      }
+    }
+    Command::InviteUser(args) =>
+    {
+      let base_url = args
+        .base_url
+        .or_else(|| host_config.webauthn_origin.clone())
+        .ok_or_else(|| eyre!("--base-url is required when webauthn_origin isn't set"))?;
+      let token = user_manager
+        .invite(args.username, args.expires)
         .await
-        .wrap_err("failed to create new user")?,
-      args.show_qr_code,
-    )?,
+        .wrap_err("failed to invite user")?;
+      println!("{}/invite?token={}", base_url.trim_end_matches('/'), token);
+    }
+    Command::ListInvites =>
+    {
+      for invite in user_manager
+        .list_invites()
+        .await
+        .wrap_err("failed to list invites")?
+      {
+        if invite.minutes_remaining > 0
+        {
+          println!(
+            "{} (expires in {} minutes)",
+            invite.username, invite.minutes_remaining
+          );
+        }
+        else
+        {
+          println!("{} (expired)", invite.username);
+        }
+      }
+    }
+    Command::RevokeInvite(args) => user_manager
+      .revoke_invites(&args.username)
+      .await
+      .wrap_err("failed to revoke invite")?,
     Command::DeleteUser(args) => user_manager
       .delete(args.username)
       .await
       .wrap_err("failed to delete user")?,
+    Command::ListSessions(args) =>
+    {
+      for session in user_manager
+        .list_sessions(&args.username)
+        .await
+        .wrap_err("failed to list sessions")?
+      {
+        println!(
+          "{}: from {}, logged in {} minutes ago, last seen {} minutes ago ({})",
+          session.id,
+          session.ip,
+          session.minutes_since_created,
+          session.minutes_since_seen,
+          session.user_agent.as_deref().unwrap_or("unknown browser")
+        );
+      }
+    }
+    Command::RevokeSessions(args) => match args.id
+    {
+      Some(id) => user_manager
+        .revoke_session(&args.username, &id)
+        .await
+        .wrap_err("failed to revoke session")?,
+      None => user_manager
+        .revoke_sessions(&args.username)
+        .await
+        .wrap_err("failed to revoke sessions")?,
+    },
+    Command::ListDevices(args) =>
+    {
+      for device in user_manager
+        .list_devices(&args.username)
+        .await
+        .wrap_err("failed to list devices")?
+      {
+        println!(
+          "{}: remembered {} days ago, expires in {} days ({})",
+          device.id,
+          device.days_since_created,
+          device.days_remaining,
+          device.user_agent.as_deref().unwrap_or("unknown browser")
+        );
+      }
+    }
+    Command::RevokeDevices(args) => match args.id
+    {
+      Some(id) => user_manager
+        .revoke_device(&args.username, &id)
+        .await
+        .wrap_err("failed to revoke device")?,
+      None => user_manager
+        .revoke_devices(&args.username)
+        .await
+        .wrap_err("failed to revoke devices")?,
+    },
     Command::ResetPassword(args) => user_manager
       .reset_password(args.username, get_password()?)
       .await
       .wrap_err("failed to reset password")?,
-    Command::ResetMFA(args) => maybe_show_qr_code(
+    Command::SetEmail(args) => user_manager
+      .set_email(args.username, args.email)
+      .await
+      .wrap_err("failed to set email")?,
+    Command::ResetMFA(args) =>
+    {
+      if args.enroll_on_login
+      {
+        user_manager
+          .require_enrollment(args.username)
+          .await
+          .wrap_err("failed to reset MFA token")?;
+        println!("The user will be asked to set up a new one time password when they next log in");
+      }
+      else
+      {
+        let (setup_code, recovery_codes) = user_manager
+          .reset_mfa(args.username)
+          .await
+          .wrap_err("failed to reset MFA token")?;
+        maybe_show_qr_code(setup_code, args.show_qr_code)?;
+        show_recovery_codes(recovery_codes);
+      }
+    }
+    Command::RegenerateRecoveryCodes(args) => show_recovery_codes(
       user_manager
-        .reset_mfa(args.username)
+        .regenerate_recovery_codes(args.username)
+        .await
+        .wrap_err("failed to regenerate recovery codes")?,
+    ),
+    Command::AddGroup(args) => user_manager
+      .add_group(args.group)
+      .await
+      .wrap_err("failed to add group")?,
+    Command::DeleteGroup(args) => user_manager
+      .delete_group(args.group)
+      .await
+      .wrap_err("failed to delete group")?,
+    Command::ListGroups =>
+    {
+      for (group, usernames) in user_manager
+        .list_groups()
+        .await
+        .wrap_err("failed to list groups")?
+      {
+        println!("{}: {}", group, usernames.join(" "));
+      }
+    }
+    Command::AddToGroup(args) => user_manager
+      .add_to_group(args.username, args.group)
+      .await
+      .wrap_err("failed to add user to group")?,
+    Command::RemoveFromGroup(args) => user_manager
+      .remove_from_group(args.username, args.group)
+      .await
+      .wrap_err("failed to remove user from group")?,
+    Command::CheckAccess(args) =>
+    {
+      let profile = user_manager
+        .get_profile(&args.username)
+        .await
+        .wrap_err("failed to look up user")?
+        .ok_or_else(|| eyre!("User {} not found!", args.username))?;
+      let allowed = AccessPolicy::new(access_config)
+        .permits(&Target::new(Some(&args.url), None), &profile.groups);
+      println!("{}", if allowed { "allowed" } else { "denied" });
+    }
+    Command::ListBans =>
+    {
+      let (manual_bans, hosts) = challenge_manager
+        .list_bans()
+        .await
+        .wrap_err("failed to list bans")?;
+      for ban in manual_bans
+      {
+        match ban.minutes_remaining
+        {
+          Some(minutes) => println!("banned {} for {} more minutes", ban.network, minutes),
+          None => println!("banned {} permanently", ban.network),
+        }
+      }
+      for host in hosts
+      {
+        println!(
+          "{} failed {} times, last {} minutes ago",
+          host.host, host.failures, host.minutes_since_last
+        );
+      }
+    }
+    Command::ShowBan(args) =>
+    {
+      let report = challenge_manager
+        .show_ban(args.host)
         .await
-        .wrap_err("failed to reset MFA token")?,
-      args.show_qr_code,
-    )?,
+        .wrap_err("failed to look up bans")?;
+      println!("recent failures: {}", report.failures);
+      println!(
+        "recent failures from {}: {}",
+        report.subnet, report.subnet_failures
+      );
+      println!("usernames tried: {}", report.usernames.join(" "));
+      println!("shown captcha: {}", report.captcha);
+      println!("logins faked: {}", report.fake_login);
+      for ban in report.manual_bans
+      {
+        match ban.minutes_remaining
+        {
+          Some(minutes) => println!("banned by {} for {} more minutes", ban.network, minutes),
+          None => println!("banned by {} permanently", ban.network),
+        }
+      }
+    }
+    Command::Unban(args) => challenge_manager
+      .unban(args.host)
+      .await
+      .wrap_err("failed to unban")?,
+    Command::Ban(args) => challenge_manager
+      .ban(args.host, args.duration)
+      .await
+      .wrap_err("failed to ban")?,
+    Command::ListSecurityKeys(args) =>
+    {
+      for name in user_manager
+        .list_security_keys(args.username)
+        .await
+        .wrap_err("failed to list security keys")?
+      {
+        println!("{}", name);
+      }
+    }
+    Command::RemoveSecurityKey(args) => user_manager
+      .remove_security_key(args.username, args.name)
+      .await
+      .wrap_err("failed to remove security key")?,
+    Command::AddOidcClient(args) =>
+    {
+      if let Some(secret) = ClientRegistry::new(db.1.clone())
+        .add(args.client_id, args.redirect_uri, args.public)
+        .await
+        .wrap_err("failed to add OpenID Connect client")?
+      {
+        println!("Client secret: {}", secret);
+      }
+    }
+    Command::DeleteOidcClient(args) => ClientRegistry::new(db.1.clone())
+      .delete(args.client_id)
+      .await
+      .wrap_err("failed to delete OpenID Connect client")?,
+    Command::ListOidcClients =>
+    {
+      for (client_id, redirect_uris) in ClientRegistry::new(db.1.clone())
+        .list()
+        .await
+        .wrap_err("failed to list OpenID Connect clients")?
+      {
+        println!("{} {}", client_id, redirect_uris.join(" "));
+      }
+    }
   }
 
   Ok(())
@@ -0,0 +1,631 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::{Arc, RwLock};
+
+use base64::{engine::general_purpose, Engine};
+use color_eyre::eyre::{eyre, Context, Result};
+use jsonwebtoken::{
+  decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rand::thread_rng;
+use rand_core::RngCore;
+use rsa::{
+  pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, LineEnding},
+  traits::PublicKeyParts,
+  RsaPrivateKey, RsaPublicKey,
+};
+use sea_orm::{
+  ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, ModelTrait, QueryFilter,
+  QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{event, instrument};
+use url::Url;
+
+use crate::{
+  config::OidcSettings,
+  entities::{oidc_authorization_code, oidc_client, signing_key},
+  user_manager::{hash_token, now},
+};
+
+const CODE_LIFETIME_SECONDS: i64 = 60;
+
+fn random_token<const N: usize>() -> String
+{
+  let mut bytes = [0; N];
+  thread_rng().fill_bytes(&mut bytes);
+  general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn redirect_with(redirect_uri: &str, params: &[(&str, &str)]) -> Result<String>
+{
+  let mut url = Url::parse(redirect_uri)?;
+  url.query_pairs_mut().extend_pairs(params);
+  Ok(url.to_string())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthorizationRequest
+{
+  pub response_type: String,
+  pub client_id: String,
+  pub redirect_uri: String,
+  pub scope: String,
+  pub state: Option<String>,
+  pub nonce: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+}
+
+pub enum AuthorizationCheck
+{
+  /// The client or redirect URI can't be trusted, so the error must not be redirected
+  Rejected,
+  /// The error can be reported back to the client's redirect URI
+  Error(&'static str),
+  Accepted,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenRequest
+{
+  pub grant_type: String,
+  // optional so a request missing them gets invalid_request rather than being rejected by the
+  // extractor
+  pub code: Option<String>,
+  pub redirect_uri: Option<String>,
+  pub client_id: Option<String>,
+  pub client_secret: Option<String>,
+  pub code_verifier: Option<String>,
+}
+
+pub enum TokenError
+{
+  InvalidRequest,
+  InvalidClient,
+  InvalidGrant,
+  UnsupportedGrantType,
+}
+
+impl TokenError
+{
+  pub fn code(&self) -> &'static str
+  {
+    match self
+    {
+      Self::InvalidRequest => "invalid_request",
+      Self::InvalidClient => "invalid_client",
+      Self::InvalidGrant => "invalid_grant",
+      Self::UnsupportedGrantType => "unsupported_grant_type",
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse
+{
+  access_token: String,
+  token_type: &'static str,
+  expires_in: u64,
+  id_token: String,
+  scope: String,
+}
+
+#[derive(Serialize)]
+pub struct UserInfo
+{
+  sub: String,
+  preferred_username: String,
+}
+
+#[derive(Serialize)]
+pub struct ProviderMetadata
+{
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: String,
+  jwks_uri: String,
+  response_types_supported: &'static [&'static str],
+  grant_types_supported: &'static [&'static str],
+  subject_types_supported: &'static [&'static str],
+  id_token_signing_alg_values_supported: &'static [&'static str],
+  scopes_supported: &'static [&'static str],
+  token_endpoint_auth_methods_supported: &'static [&'static str],
+  code_challenge_methods_supported: &'static [&'static str],
+  claims_supported: &'static [&'static str],
+}
+
+#[derive(Serialize, Clone)]
+pub struct Jwk
+{
+  kty: &'static str,
+  #[serde(rename = "use")]
+  usage: &'static str,
+  alg: &'static str,
+  kid: String,
+  n: String,
+  e: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JwkSet
+{
+  keys: Vec<Jwk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims
+{
+  iss: String,
+  sub: String,
+  aud: String,
+  exp: u64,
+  iat: u64,
+  preferred_username: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  nonce: Option<String>,
+  /// Only present on access tokens
+  #[serde(skip_serializing_if = "Option::is_none")]
+  scope: Option<String>,
+}
+
+struct Key
+{
+  kid: String,
+  encoding: EncodingKey,
+  decoding: DecodingKey,
+  jwk: Jwk,
+}
+
+impl Key
+{
+  fn from_model(model: &signing_key::Model) -> Result<Self>
+  {
+    let private_key = RsaPrivateKey::from_pkcs1_pem(&model.private_key)?;
+    let public_key = RsaPublicKey::from(&private_key);
+    let n = public_key.n().to_bytes_be();
+    let e = public_key.e().to_bytes_be();
+    Ok(Self {
+      kid: model.kid.clone(),
+      encoding: EncodingKey::from_rsa_pem(model.private_key.as_bytes())?,
+      decoding: DecodingKey::from_rsa_raw_components(&n, &e),
+      jwk: Jwk {
+        kty: "RSA",
+        usage: "sig",
+        alg: "RS256",
+        kid: model.kid.clone(),
+        n: general_purpose::URL_SAFE_NO_PAD.encode(n),
+        e: general_purpose::URL_SAFE_NO_PAD.encode(e),
+      },
+    })
+  }
+}
+
+#[derive(Clone)]
+pub struct ClientRegistry
+{
+  db: DatabaseConnection,
+}
+
+impl ClientRegistry
+{
+  pub fn new(db: DatabaseConnection) -> Self
+  {
+    Self { db }
+  }
+
+  /// Registers a client, returning the generated secret for confidential clients
+  pub async fn add(
+    &self,
+    client_id: String,
+    redirect_uris: Vec<String>,
+    public: bool,
+  ) -> Result<Option<String>>
+  {
+    for redirect_uri in &redirect_uris
+    {
+      Url::parse(redirect_uri)
+        .wrap_err_with(|| format!("invalid redirect uri {}", redirect_uri))?;
+    }
+    let secret = (!public).then(random_token::<32>);
+    oidc_client::ActiveModel {
+      client_id: Set(client_id),
+      secret_hash: Set(secret.as_deref().map(hash_token)),
+      redirect_uris: Set(redirect_uris.join(" ")),
+    }
+    .insert(&self.db)
+    .await?;
+    Ok(secret)
+  }
+
+  pub async fn delete(&self, client_id: String) -> Result<()>
+  {
+    self
+      .get(&client_id)
+      .await?
+      .ok_or_else(|| eyre!("Client {} not found!", client_id))?
+      .delete(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn list(&self) -> Result<Vec<(String, Vec<String>)>>
+  {
+    Ok(
+      oidc_client::Entity::find()
+        .all(&self.db)
+        .await?
+        .into_iter()
+        .map(|client| {
+          let redirect_uris = client
+            .redirect_uris
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+          (client.client_id, redirect_uris)
+        })
+        .collect(),
+    )
+  }
+
+  async fn get(&self, client_id: &str) -> Result<Option<oidc_client::Model>>
+  {
+    Ok(
+      oidc_client::Entity::find_by_id(client_id.to_owned())
+        .one(&self.db)
+        .await?,
+    )
+  }
+}
+
+#[derive(Clone)]
+pub struct OidcProvider
+{
+  db: DatabaseConnection,
+  clients: ClientRegistry,
+  issuer: String,
+  token_lifetime_seconds: u64,
+  /// Newest first.  Other nodes may add keys, so these are reloaded when one turns up missing
+  keys: Arc<RwLock<Vec<Key>>>,
+}
+
+impl OidcProvider
+{
+  pub async fn new(db: DatabaseConnection, settings: OidcSettings) -> Result<Self>
+  {
+    let mut keys = Self::load_keys(&db).await?;
+    // nodes starting together may each add one, so the table is read back rather than the new
+    // key used on its own
+    if keys.is_empty()
+    {
+      event!(tracing::Level::INFO, "generating new OIDC signing key");
+      let private_key = RsaPrivateKey::new(&mut thread_rng(), 2048)?;
+      signing_key::ActiveModel {
+        kid: Set(random_token::<8>()),
+        private_key: Set(private_key.to_pkcs1_pem(LineEnding::LF)?.to_string()),
+        created: Set(now()),
+      }
+      .insert(&db)
+      .await?;
+      keys = Self::load_keys(&db).await?;
+    }
+
+    Ok(Self {
+      clients: ClientRegistry::new(db.clone()),
+      db,
+      issuer: settings.issuer.trim_end_matches('/').to_owned(),
+      token_lifetime_seconds: settings.token_lifetime_seconds.unwrap_or(3600),
+      keys: Arc::new(RwLock::new(keys)),
+    })
+  }
+
+  async fn load_keys(db: &DatabaseConnection) -> Result<Vec<Key>>
+  {
+    signing_key::Entity::find()
+      .order_by_desc(signing_key::Column::Created)
+      .order_by_asc(signing_key::Column::Kid)
+      .all(db)
+      .await?
+      .iter()
+      .map(Key::from_model)
+      .collect()
+  }
+
+  pub fn metadata(&self) -> ProviderMetadata
+  {
+    ProviderMetadata {
+      issuer: self.issuer.clone(),
+      authorization_endpoint: format!("{}/oidc/authorize", self.issuer),
+      token_endpoint: format!("{}/oidc/token", self.issuer),
+      userinfo_endpoint: format!("{}/oidc/userinfo", self.issuer),
+      jwks_uri: format!("{}/oidc/jwks", self.issuer),
+      response_types_supported: &["code"],
+      grant_types_supported: &["authorization_code"],
+      subject_types_supported: &["public"],
+      id_token_signing_alg_values_supported: &["RS256"],
+      scopes_supported: &["openid", "profile"],
+      token_endpoint_auth_methods_supported: &["client_secret_basic", "client_secret_post", "none"],
+      code_challenge_methods_supported: &["S256"],
+      claims_supported: &[
+        "iss",
+        "sub",
+        "aud",
+        "exp",
+        "iat",
+        "nonce",
+        "preferred_username",
+      ],
+    }
+  }
+
+  async fn reload_keys(&self) -> Result<()>
+  {
+    let keys = Self::load_keys(&self.db).await?;
+    *self
+      .keys
+      .write()
+      .map_err(|_| eyre!("signing keys lock poisoned"))? = keys;
+    Ok(())
+  }
+
+  /// Read fresh each time, so keys added by other nodes are published too
+  pub async fn jwks(&self) -> Result<JwkSet>
+  {
+    self.reload_keys().await?;
+    let keys = self
+      .keys
+      .read()
+      .map_err(|_| eyre!("signing keys lock poisoned"))?;
+    Ok(JwkSet {
+      keys: keys.iter().map(|key| key.jwk.clone()).collect(),
+    })
+  }
+
+  #[instrument(skip(self))]
+  pub async fn check_authorization(
+    &self,
+    request: &AuthorizationRequest,
+  ) -> Result<AuthorizationCheck>
+  {
+    let client = match self.clients.get(&request.client_id).await?
+    {
+      Some(client) => client,
+      None => return Ok(AuthorizationCheck::Rejected),
+    };
+    if !client
+      .redirect_uris
+      .split_whitespace()
+      .any(|redirect_uri| redirect_uri == request.redirect_uri)
+    {
+      return Ok(AuthorizationCheck::Rejected);
+    }
+
+    Ok(
+      if request.response_type != "code"
+      {
+        AuthorizationCheck::Error("unsupported_response_type")
+      }
+      else if !request
+        .scope
+        .split_whitespace()
+        .any(|scope| scope == "openid")
+      {
+        AuthorizationCheck::Error("invalid_scope")
+      }
+      else if request.code_challenge.is_some()
+        && request.code_challenge_method.as_deref() != Some("S256")
+      {
+        AuthorizationCheck::Error("invalid_request")
+      }
+      else if client.secret_hash.is_none() && request.code_challenge.is_none()
+      {
+        // public clients have nothing else proving they started the flow
+        AuthorizationCheck::Error("invalid_request")
+      }
+      else
+      {
+        AuthorizationCheck::Accepted
+      },
+    )
+  }
+
+  #[instrument(skip(self))]
+  pub async fn issue_code(&self, request: &AuthorizationRequest, username: &str) -> Result<String>
+  {
+    oidc_authorization_code::Entity::delete_many()
+      .filter(oidc_authorization_code::Column::Expires.lt(now()))
+      .exec(&self.db)
+      .await?;
+
+    let code = random_token::<32>();
+    oidc_authorization_code::ActiveModel {
+      code_hash: Set(hash_token(&code)),
+      client_id: Set(request.client_id.clone()),
+      username: Set(username.to_owned()),
+      redirect_uri: Set(request.redirect_uri.clone()),
+      scope: Set(request.scope.clone()),
+      nonce: Set(request.nonce.clone()),
+      code_challenge: Set(request.code_challenge.clone()),
+      expires: Set(now() + CODE_LIFETIME_SECONDS),
+    }
+    .insert(&self.db)
+    .await?;
+    Ok(code)
+  }
+
+  #[instrument(skip(self, request, client_secret))]
+  pub async fn exchange_code(
+    &self,
+    request: &TokenRequest,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+  ) -> Result<Result<TokenResponse, TokenError>>
+  {
+    if request.grant_type != "authorization_code"
+    {
+      return Ok(Err(TokenError::UnsupportedGrantType));
+    }
+    let (code, redirect_uri) = match (&request.code, &request.redirect_uri)
+    {
+      (Some(code), Some(redirect_uri)) => (code, redirect_uri),
+      _ => return Ok(Err(TokenError::InvalidRequest)),
+    };
+    let client_id = match client_id.or(request.client_id.as_deref())
+    {
+      Some(client_id) => client_id,
+      None => return Ok(Err(TokenError::InvalidRequest)),
+    };
+    let client = match self.clients.get(client_id).await?
+    {
+      Some(client) => client,
+      None => return Ok(Err(TokenError::InvalidClient)),
+    };
+    if let Some(secret_hash) = &client.secret_hash
+    {
+      let client_secret = client_secret.or(request.client_secret.as_deref());
+      if client_secret.map(hash_token).as_ref() != Some(secret_hash)
+      {
+        return Ok(Err(TokenError::InvalidClient));
+      }
+    }
+
+    let code_hash = hash_token(code);
+    let grant = match oidc_authorization_code::Entity::find_by_id(code_hash.clone())
+      .one(&self.db)
+      .await?
+    {
+      Some(grant) => grant,
+      None => return Ok(Err(TokenError::InvalidGrant)),
+    };
+    // codes are single use even when the exchange fails
+    let redeemed = oidc_authorization_code::Entity::delete_by_id(code_hash)
+      .exec(&self.db)
+      .await?
+      .rows_affected
+      == 1;
+    let pkce_valid = match (&grant.code_challenge, &request.code_verifier)
+    {
+      (Some(challenge), Some(verifier)) =>
+      {
+        general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == *challenge
+      }
+      (None, None) => true,
+      _ => false,
+    };
+    if !redeemed
+      || !pkce_valid
+      || grant.client_id != client.client_id
+      || grant.redirect_uri != *redirect_uri
+      || grant.expires < now()
+    {
+      return Ok(Err(TokenError::InvalidGrant));
+    }
+
+    let issued_at = now() as u64;
+    let claims = Claims {
+      iss: self.issuer.clone(),
+      sub: grant.username.clone(),
+      aud: grant.client_id.clone(),
+      exp: issued_at + self.token_lifetime_seconds,
+      iat: issued_at,
+      preferred_username: grant.username,
+      nonce: grant.nonce,
+      scope: None,
+    };
+    let id_token = self.sign(&claims)?;
+    let access_token = self.sign(&Claims {
+      nonce: None,
+      scope: Some(grant.scope.clone()),
+      ..claims
+    })?;
+    event!(tracing::Level::INFO, "issued tokens");
+    Ok(Ok(TokenResponse {
+      access_token,
+      token_type: "Bearer",
+      expires_in: self.token_lifetime_seconds,
+      id_token,
+      scope: grant.scope,
+    }))
+  }
+
+  fn sign(&self, claims: &Claims) -> Result<String>
+  {
+    let keys = self
+      .keys
+      .read()
+      .map_err(|_| eyre!("signing keys lock poisoned"))?;
+    let key = keys
+      .first()
+      .ok_or_else(|| eyre!("no signing key available"))?;
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(key.kid.clone());
+    Ok(encode(&header, claims, &key.encoding)?)
+  }
+
+  pub async fn user_info(&self, access_token: &str) -> Result<Option<UserInfo>>
+  {
+    let kid = match decode_header(access_token)
+      .ok()
+      .and_then(|header| header.kid)
+    {
+      Some(kid) => kid,
+      None => return Ok(None),
+    };
+    // the token may have been signed by another node with a key added since these were loaded
+    if !self.has_key(&kid)?
+    {
+      self.reload_keys().await?;
+    }
+    let keys = self
+      .keys
+      .read()
+      .map_err(|_| eyre!("signing keys lock poisoned"))?;
+    let key = match keys.iter().find(|key| key.kid == kid)
+    {
+      Some(key) => key,
+      None => return Ok(None),
+    };
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[&self.issuer]);
+    let claims = match decode::<Claims>(access_token, &key.decoding, &validation)
+    {
+      Ok(token) => token.claims,
+      Err(_) => return Ok(None),
+    };
+    // id tokens are signed with the same key, but carry no scope
+    Ok(claims.scope.map(|_| UserInfo {
+      sub: claims.sub,
+      preferred_username: claims.preferred_username,
+    }))
+  }
+
+  fn has_key(&self, kid: &str) -> Result<bool>
+  {
+    Ok(
+      self
+        .keys
+        .read()
+        .map_err(|_| eyre!("signing keys lock poisoned"))?
+        .iter()
+        .any(|key| key.kid == kid),
+    )
+  }
+}
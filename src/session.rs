@@ -127,6 +127,12 @@ impl SessionBackendStorage
     }
   }
 
+  /// Sessions kept in memory don't survive a restart
+  pub fn is_in_memory(&self) -> bool
+  {
+    matches!(self, Self::InMemory(_))
+  }
+
   pub async fn cleanup(&self) -> sqlx::Result<()>
   {
     match self
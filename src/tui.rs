@@ -23,7 +23,7 @@ use color_eyre::{
 use std::io;
 use zxcvbn::{feedback::Suggestion, zxcvbn};
 
-use crate::user_manager::SetupCode;
+use crate::user_manager::{RecoveryCodes, SetupCode, MIN_PASSWORD_SCORE};
 
 pub fn get_password() -> Result<String, io::Error>
 {
@@ -81,7 +81,7 @@ pub fn get_password() -> Result<String, io::Error>
             });
           }
         }
-        if score < 3
+        if score < MIN_PASSWORD_SCORE
         {
           println!("Password is too weak - try again");
           continue;
@@ -116,3 +116,12 @@ pub fn maybe_show_qr_code(code: SetupCode, show: bool) -> Result<()>
   }
   Ok(())
 }
+
+pub fn show_recovery_codes(codes: RecoveryCodes)
+{
+  println!("Recovery codes (each may be used once in place of a one time password):");
+  for code in codes.get_codes()
+  {
+    println!("  {}", code);
+  }
+}
@@ -0,0 +1,144 @@
+/*
+ruuth: simple auth_request backend
+Copyright (C) 2022 Joe Dillon
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use color_eyre::eyre::{eyre, Context, Result};
+use openidconnect::{
+  core::{CoreClient, CoreProviderMetadata, CoreResponseType},
+  reqwest::async_http_client,
+  AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce,
+  PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, instrument};
+
+use crate::config::{UpstreamClaim, UpstreamSettings};
+
+/// Everything needed to finish a login once the identity provider redirects back
+#[derive(Serialize, Deserialize)]
+pub struct UpstreamLoginState
+{
+  csrf_token: String,
+  nonce: String,
+  pkce_verifier: String,
+  pub url: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct UpstreamProvider
+{
+  client: CoreClient,
+  settings: UpstreamSettings,
+}
+
+impl UpstreamProvider
+{
+  pub async fn new(settings: UpstreamSettings) -> Result<Self>
+  {
+    let metadata = CoreProviderMetadata::discover_async(
+      IssuerUrl::new(settings.issuer.clone()).wrap_err("invalid issuer url")?,
+      async_http_client,
+    )
+    .await
+    .wrap_err("failed to discover identity provider")?;
+    let client = CoreClient::from_provider_metadata(
+      metadata,
+      ClientId::new(settings.client_id.clone()),
+      settings.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(
+      RedirectUrl::new(settings.redirect_url.clone()).wrap_err("invalid redirect url")?,
+    );
+
+    Ok(Self { client, settings })
+  }
+
+  pub fn name(&self) -> &str
+  {
+    &self.settings.name
+  }
+
+  pub fn auto_provision(&self) -> bool
+  {
+    self.settings.auto_provision
+  }
+
+  pub fn start(&self, url: Option<String>) -> (String, UpstreamLoginState)
+  {
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (authorize_url, csrf_token, nonce) = self
+      .client
+      .authorize_url(
+        AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+        CsrfToken::new_random,
+        Nonce::new_random,
+      )
+      .add_scopes(self.settings.scopes.iter().cloned().map(Scope::new))
+      .set_pkce_challenge(pkce_challenge)
+      .url();
+
+    (
+      authorize_url.to_string(),
+      UpstreamLoginState {
+        csrf_token: csrf_token.secret().clone(),
+        nonce: nonce.secret().clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+        url,
+      },
+    )
+  }
+
+  /// Exchanges the authorization code, returning the local username the identity provider vouched for
+  #[instrument(skip(self, code, csrf_token, state))]
+  pub async fn finish(
+    &self,
+    code: String,
+    csrf_token: &str,
+    state: UpstreamLoginState,
+  ) -> Result<Option<String>>
+  {
+    if csrf_token != state.csrf_token
+    {
+      event!(
+        tracing::Level::WARN,
+        "state mismatch in identity provider callback"
+      );
+      return Ok(None);
+    }
+
+    let response = self
+      .client
+      .exchange_code(AuthorizationCode::new(code))
+      .set_pkce_verifier(PkceCodeVerifier::new(state.pkce_verifier))
+      .request_async(async_http_client)
+      .await
+      .wrap_err("failed to exchange authorization code")?;
+    let claims = response
+      .id_token()
+      .ok_or_else(|| eyre!("identity provider did not return an id token"))?
+      .claims(&self.client.id_token_verifier(), &Nonce::new(state.nonce))
+      .wrap_err("invalid id token")?;
+
+    Ok(match self.settings.username_claim
+    {
+      UpstreamClaim::Subject => Some(claims.subject().as_str().to_owned()),
+      UpstreamClaim::PreferredUsername => claims
+        .preferred_username()
+        .map(|username| username.as_str().to_owned()),
+    })
+  }
+}
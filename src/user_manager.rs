@@ -18,17 +18,79 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use askama::filters::urlencode;
+use axum_sessions::async_session::serde_json;
 use base32::Alphabet;
-use color_eyre::eyre::{eyre, Result};
-use qrcode::{render::unicode, types::QrError, QrCode};
+use base64::{engine::general_purpose, Engine};
+use color_eyre::eyre::{eyre, Context, Result};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use qrcode::{
+  render::{svg, unicode},
+  types::QrError,
+  QrCode,
+};
 use rand::thread_rng;
-use rand_core::CryptoRngCore;
-use sea_orm::{ActiveModelTrait, DatabaseConnection, EntityTrait, ModelTrait, Set};
-use std::time::{SystemTime, UNIX_EPOCH};
-use totp_lite::{totp_custom, Sha1, DEFAULT_STEP};
+use rand_core::{CryptoRngCore, RngCore};
+use sea_orm::{
+  sea_query::Expr, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, EntityTrait,
+  ModelTrait, QueryFilter, QueryOrder, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::{
+  net::IpAddr,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use totp_lite::{totp_custom, Sha1, Sha256, DEFAULT_STEP};
 use tracing::{event, instrument};
+use webauthn_rs::{
+  prelude::{
+    CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+    RequestChallengeResponse, SecurityKey, SecurityKeyAuthentication, SecurityKeyRegistration, Url,
+    Uuid,
+  },
+  Webauthn, WebauthnBuilder,
+};
+use zxcvbn::zxcvbn;
 
-use crate::entities::{prelude::*, user};
+use crate::{
+  config::{MfaSettings, PasswordBackend, TotpAlgorithm},
+  entities::{
+    group, group_member, invite, oidc_authorization_code, prelude::*, recovery_code, security_key,
+    trusted_device, user, user_session,
+  },
+  ldap,
+};
+
+/// Swaps the credential ID in a serialized security key for one of the same length drawn from
+/// the seed
+fn replace_credential_id(value: &mut serde_json::Value, seed: &[u8]) -> bool
+{
+  match value
+  {
+    serde_json::Value::Object(fields) =>
+    {
+      if let Some(serde_json::Value::String(id)) = fields.get_mut("cred_id")
+      {
+        let length = general_purpose::URL_SAFE_NO_PAD
+          .decode(id.trim_end_matches('='))
+          .map_or(seed.len(), |bytes| bytes.len());
+        let bytes = seed
+          .iter()
+          .copied()
+          .cycle()
+          .take(length)
+          .collect::<Vec<_>>();
+        *id = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        return true;
+      }
+      fields
+        .values_mut()
+        .any(|field| replace_credential_id(field, seed))
+    }
+    _ => false,
+  }
+}
 
 fn fill_bytes<R: CryptoRngCore, const N: usize>(rng: &mut R) -> [u8; N]
 {
@@ -37,29 +99,146 @@ fn fill_bytes<R: CryptoRngCore, const N: usize>(rng: &mut R) -> [u8; N]
   arr
 }
 
-pub struct TotpSecret([u8; 128]);
+fn algorithm_name(algorithm: TotpAlgorithm) -> &'static str
+{
+  match algorithm
+  {
+    TotpAlgorithm::Sha1 => "SHA1",
+    TotpAlgorithm::Sha256 => "SHA256",
+    TotpAlgorithm::Sha512 => "SHA512",
+  }
+}
+
+fn parse_algorithm(name: &str) -> Option<TotpAlgorithm>
+{
+  match name
+  {
+    "SHA1" => Some(TotpAlgorithm::Sha1),
+    "SHA256" => Some(TotpAlgorithm::Sha256),
+    "SHA512" => Some(TotpAlgorithm::Sha512),
+    _ => None,
+  }
+}
+
+pub struct TotpSecret
+{
+  secret: Vec<u8>,
+  algorithm: TotpAlgorithm,
+  digits: u32,
+  period: u64,
+}
 
 impl TotpSecret
 {
-  pub fn new() -> Self
+  pub fn new(settings: &MfaSettings) -> Self
   {
-    Self(fill_bytes(&mut thread_rng()))
+    let mut secret = vec![0; settings.totp_secret_bytes];
+    thread_rng().fill_bytes(&mut secret);
+    Self {
+      secret,
+      algorithm: settings.totp_algorithm,
+      digits: settings.totp_digits,
+      period: settings.totp_period,
+    }
+  }
+
+  /// The pending secret with the parameters it was shown with.  Ones generated before those
+  /// were stored take the current settings
+  fn pending(user: &user::Model, settings: &MfaSettings) -> Option<Self>
+  {
+    user.pending_totp_secret.as_ref().map(|secret| Self {
+      secret: secret.clone(),
+      algorithm: user
+        .pending_totp_algorithm
+        .as_deref()
+        .and_then(parse_algorithm)
+        .unwrap_or(settings.totp_algorithm),
+      digits: user
+        .pending_totp_digits
+        .map_or(settings.totp_digits, |digits| digits as u32),
+      period: user
+        .pending_totp_period
+        .map_or(settings.totp_period, |period| period as u64),
+    })
+  }
+
+  fn from_user(user: &user::Model) -> Self
+  {
+    // enrollments from before these were configurable have no parameters stored
+    Self {
+      secret: user.totp_secret.clone(),
+      algorithm: user
+        .totp_algorithm
+        .as_deref()
+        .and_then(parse_algorithm)
+        .unwrap_or(TotpAlgorithm::Sha1),
+      digits: user.totp_digits.map_or(6, |digits| digits as u32),
+      period: user
+        .totp_period
+        .map_or(DEFAULT_STEP, |period| period as u64),
+    }
   }
 
   pub fn get_setup_code(&self, username: &str, issuer: &str) -> SetupCode
   {
-    SetupCode(format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period=30",
-      secret = base32::encode(Alphabet::RFC4648 { padding: true }, &self.0),
+    SetupCode(format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm={algorithm}&digits={digits}&period={period}",
+      secret = base32::encode(Alphabet::RFC4648 { padding: true }, &self.secret),
       issuer = urlencode(issuer).unwrap_or_default(),
-      username = urlencode(username).unwrap_or_default()))
+      username = urlencode(username).unwrap_or_default(),
+      algorithm = algorithm_name(self.algorithm),
+      digits = self.digits,
+      period = self.period))
   }
-}
 
-impl Into<Vec<u8>> for TotpSecret
-{
-  fn into(self) -> Vec<u8>
+  fn current_step(&self) -> u64
+  {
+    SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_secs()
+      / self.period
+  }
+
+  fn generate(&self, step: u64) -> String
+  {
+    let seconds = step * self.period;
+    match self.algorithm
+    {
+      TotpAlgorithm::Sha1 => totp_custom::<Sha1>(self.period, self.digits, &self.secret, seconds),
+      TotpAlgorithm::Sha256 =>
+      {
+        totp_custom::<Sha256>(self.period, self.digits, &self.secret, seconds)
+      }
+      TotpAlgorithm::Sha512 =>
+      {
+        totp_custom::<Sha512>(self.period, self.digits, &self.secret, seconds)
+      }
+    }
+  }
+
+  fn store(self, user: &mut user::ActiveModel)
   {
-    self.0.to_vec()
+    user.totp_secret = Set(self.secret);
+    user.totp_algorithm = Set(Some(algorithm_name(self.algorithm).to_owned()));
+    user.totp_digits = Set(Some(self.digits as i32));
+    user.totp_period = Set(Some(self.period as i64));
+    user.last_totp_step = Set(None);
+  }
+
+  fn store_pending(&self, user: &mut user::ActiveModel)
+  {
+    user.pending_totp_secret = Set(Some(self.secret.clone()));
+    user.pending_totp_algorithm = Set(Some(algorithm_name(self.algorithm).to_owned()));
+    user.pending_totp_digits = Set(Some(self.digits as i32));
+    user.pending_totp_period = Set(Some(self.period as i64));
+  }
+
+  fn clear_pending(user: &mut user::ActiveModel)
+  {
+    user.pending_totp_secret = Set(None);
+    user.pending_totp_algorithm = Set(None);
+    user.pending_totp_digits = Set(None);
+    user.pending_totp_period = Set(None);
   }
 }
 
@@ -78,12 +257,97 @@ impl SetupCode
     )
   }
 
+  pub fn get_svg_qr_code(&self) -> Result<String, QrError>
+  {
+    Ok(
+      QrCode::new(&self.0)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .dark_color(svg::Color("#000000"))
+        .light_color(svg::Color("#ffffff"))
+        .build(),
+    )
+  }
+
   pub fn get_raw_code(&self) -> String
   {
     self.0.clone()
   }
 }
 
+pub struct RecoveryCodes(Vec<String>);
+
+impl RecoveryCodes
+{
+  const COUNT: usize = 10;
+  const LABEL_LENGTH: usize = 4;
+  const SECRET_LENGTH: usize = 10;
+
+  fn new() -> Self
+  {
+    Self(
+      (0..Self::COUNT)
+        .map(|_| {
+          let code = base32::encode(Alphabet::Crockford, &fill_bytes::<_, 9>(&mut thread_rng()))
+            .to_lowercase();
+          format!("{}-{}-{}", &code[0..4], &code[4..9], &code[9..14])
+        })
+        .collect(),
+    )
+  }
+
+  /// Splits a normalized code into its label and the secret that's hashed.  Codes from before
+  /// labels were added are all secret
+  fn split(code: &str) -> (Option<&str>, &str)
+  {
+    if code.len() == Self::LABEL_LENGTH + Self::SECRET_LENGTH
+    {
+      let (label, secret) = code.split_at(Self::LABEL_LENGTH);
+      (Some(label), secret)
+    }
+    else
+    {
+      (None, code)
+    }
+  }
+
+  fn normalize(code: &str) -> String
+  {
+    code
+      .chars()
+      .filter(char::is_ascii_alphanumeric)
+      .collect::<String>()
+      .to_uppercase()
+  }
+
+  pub fn get_codes(&self) -> &[String]
+  {
+    &self.0
+  }
+}
+
+/// Users authenticated by an external backend get a local password nobody knows
+fn random_password() -> String
+{
+  base32::encode(
+    Alphabet::RFC4648 { padding: false },
+    &fill_bytes::<_, 32>(&mut thread_rng()),
+  )
+}
+
+pub fn now() -> i64
+{
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+/// Tokens, codes and client secrets are random and high entropy, so a plain digest is enough
+pub fn hash_token(token: &str) -> String
+{
+  general_purpose::STANDARD.encode(Sha512::digest(token.as_bytes()))
+}
+
 fn create_hasher<'a>(pepper: &'a [u8]) -> Result<Argon2<'a>, argon2::Error>
 {
   Argon2::new_with_secret(
@@ -94,34 +358,288 @@ fn create_hasher<'a>(pepper: &'a [u8]) -> Result<Argon2<'a>, argon2::Error>
   )
 }
 
+/// Lowest zxcvbn score accepted for a new password
+pub const MIN_PASSWORD_SCORE: u8 = 3;
+
+pub fn password_strong_enough(password: &str, user_inputs: &[&str]) -> bool
+{
+  zxcvbn(password, user_inputs).map_or(false, |entropy| entropy.score() >= MIN_PASSWORD_SCORE)
+}
+
+pub enum SecondFactor<'a>
+{
+  Passcode(&'a str),
+  SecurityKey
+  {
+    credential: &'a PublicKeyCredential,
+    challenged_username: &'a str,
+    state: &'a SecurityKeyAuthentication,
+  },
+  /// The signed token from a remembered device's cookie
+  TrustedDevice(&'a str),
+}
+
+#[derive(Clone)]
+pub struct Profile
+{
+  pub email: Option<String>,
+  pub groups: Vec<String>,
+  pub session_epoch: i64,
+}
+
+pub struct InviteSummary
+{
+  pub username: String,
+  /// Negative once the invite has expired
+  pub minutes_remaining: i64,
+}
+
+pub struct SessionSummary
+{
+  pub id: String,
+  pub minutes_since_created: i64,
+  pub minutes_since_seen: i64,
+  pub ip: String,
+  pub user_agent: Option<String>,
+}
+
+pub struct DeviceSummary
+{
+  pub id: String,
+  pub days_since_created: i64,
+  pub days_remaining: i64,
+  pub user_agent: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceClaims
+{
+  sub: String,
+  jti: String,
+  exp: i64,
+}
+
 #[derive(Clone)]
 pub struct UserManager
 {
   db: DatabaseConnection,
   issuer: String,
   pepper: Vec<u8>,
+  webauthn: Option<Arc<Webauthn>>,
+  mfa: MfaSettings,
+  password_backend: PasswordBackend,
 }
 
 impl UserManager
 {
-  pub fn new(db: DatabaseConnection, issuer: String, pepper: Vec<u8>) -> Result<Self>
+  pub fn new(
+    db: DatabaseConnection,
+    issuer: String,
+    pepper: Vec<u8>,
+    webauthn_origin: Option<String>,
+    mfa: MfaSettings,
+    password_backend: PasswordBackend,
+  ) -> Result<Self>
+  {
+    if mfa.totp_digits != 6 && mfa.totp_digits != 8
+    {
+      return Err(eyre!("totp_digits must be either 6 or 8"));
+    }
+    if mfa.totp_period == 0
+    {
+      return Err(eyre!("totp_period must be at least one second"));
+    }
+    if mfa.totp_secret_bytes < 16
+    {
+      return Err(eyre!("totp_secret_bytes must be at least 16"));
+    }
+
+    let webauthn = webauthn_origin
+      .map(|origin| -> Result<Arc<Webauthn>> {
+        let origin = Url::parse(&origin).wrap_err("invalid webauthn origin")?;
+        Ok(Arc::new(
+          WebauthnBuilder::new(&issuer, &origin)?
+            .rp_name(&issuer)
+            .build()?,
+        ))
+      })
+      .transpose()?;
+    Ok(Self {
+      db,
+      issuer,
+      pepper,
+      webauthn,
+      mfa,
+      password_backend,
+    })
+  }
+
+  pub fn manages_passwords(&self) -> bool
   {
-    Ok(Self { db, issuer, pepper })
+    matches!(self.password_backend, PasswordBackend::Local)
   }
 
-  pub async fn register(&self, username: String, password: String) -> Result<SetupCode>
+  pub async fn register(
+    &self,
+    username: String,
+    password: Option<String>,
+  ) -> Result<(SetupCode, RecoveryCodes)>
   {
-    let totp_secret = TotpSecret::new();
+    let password = password.unwrap_or_else(random_password);
+    let totp_secret = TotpSecret::new(&self.mfa);
     let setup_code = totp_secret.get_setup_code(&username, &self.issuer);
-    user::ActiveModel {
+    let mut user = user::ActiveModel {
+      username: Set(username),
+      password_hash: Set(self.hash_password(password)?),
+      ..Default::default()
+    };
+    totp_secret.store(&mut user);
+    let user = user.insert(&self.db).await?;
+
+    Ok((
+      setup_code,
+      self.replace_recovery_codes(&user.username).await?,
+    ))
+  }
+
+  /// Creates a user who sets up their own TOTP secret the first time they log in.  Recovery
+  /// codes are handed out once they have
+  pub async fn register_pending(&self, username: String, password: Option<String>) -> Result<()>
+  {
+    let password = password.unwrap_or_else(random_password);
+    let mut user = user::ActiveModel {
       username: Set(username),
       password_hash: Set(self.hash_password(password)?),
-      totp_secret: Set(totp_secret.into()),
+      totp_secret: Set(Vec::new()),
+      ..Default::default()
+    };
+    TotpSecret::new(&self.mfa).store_pending(&mut user);
+    user.insert(&self.db).await?;
+    Ok(())
+  }
+
+  /// Creates an account the user finishes setting up themselves, returning the single use
+  /// token for their invite link.  Only a hash of the token is stored
+  pub async fn invite(&self, username: String, lifetime_minutes: i64) -> Result<String>
+  {
+    self.register_pending(username.clone(), None).await?;
+    let token = base32::encode(
+      Alphabet::RFC4648 { padding: false },
+      &fill_bytes::<_, 32>(&mut thread_rng()),
+    );
+    invite::ActiveModel {
+      token_hash: Set(hash_token(&token)),
+      username: Set(username),
+      created: Set(now()),
+      expires: Set(now().saturating_add(lifetime_minutes.saturating_mul(60))),
     }
     .insert(&self.db)
     .await?;
+    Ok(token)
+  }
+
+  /// The user an invite was issued to, if the token is still valid
+  pub async fn find_invite(&self, token: &str) -> Result<Option<String>>
+  {
+    Ok(
+      Invite::find_by_id(hash_token(token))
+        .filter(invite::Column::Expires.gt(now()))
+        .one(&self.db)
+        .await?
+        .map(|invite| invite.username),
+    )
+  }
+
+  /// Uses up an invite, setting the password the user chose.  Returns who the invite was for,
+  /// or None if the token is invalid, expired or already used
+  #[instrument(skip(self, token, password))]
+  pub async fn redeem_invite(&self, token: &str, password: String) -> Result<Option<String>>
+  {
+    let username = match self.find_invite(token).await?
+    {
+      Some(username) => username,
+      None => return Ok(None),
+    };
+    // deleting first means two requests racing on one token can't both get through
+    if Invite::delete_by_id(hash_token(token))
+      .exec(&self.db)
+      .await?
+      .rows_affected
+      != 1
+    {
+      return Ok(None);
+    }
+    if self.manages_passwords()
+    {
+      self.reset_password(username.clone(), password).await?;
+    }
+    Ok(Some(username))
+  }
 
-    Ok(setup_code)
+  pub async fn list_invites(&self) -> Result<Vec<InviteSummary>>
+  {
+    Ok(
+      Invite::find()
+        .all(&self.db)
+        .await?
+        .into_iter()
+        .map(|invite| InviteSummary {
+          username: invite.username,
+          // rounded up, so an invite with seconds left isn't reported as expired
+          minutes_remaining: (invite.expires - now() + 59).div_euclid(60),
+        })
+        .collect(),
+    )
+  }
+
+  /// Invalidates every outstanding invite for a user, along with the account if it was never
+  /// set up.  Otherwise a directory password alone would still be enough to enrol it
+  pub async fn revoke_invites(&self, username: &str) -> Result<()>
+  {
+    let result = Invite::delete_many()
+      .filter(invite::Column::Username.eq(username))
+      .exec(&self.db)
+      .await?;
+    if result.rows_affected == 0
+    {
+      return Err(eyre!("No invites found for {}", username));
+    }
+    let user = User::find_by_id(username.to_owned()).one(&self.db).await?;
+    if user.map_or(false, |user| user.totp_secret.is_empty())
+    {
+      event!(
+        tracing::Level::INFO,
+        "deleting account that was never set up"
+      );
+      self.delete(username.to_owned()).await?;
+    }
+    Ok(())
+  }
+
+  /// Checks a username vouched for by an external identity provider has a local user,
+  /// creating one when provisioning is allowed
+  #[instrument(skip(self))]
+  pub async fn find_or_provision(&self, username: String, provision: bool) -> Result<bool>
+  {
+    if User::find_by_id(username.clone())
+      .one(&self.db)
+      .await?
+      .is_some()
+    {
+      Ok(true)
+    }
+    else if provision
+    {
+      event!(tracing::Level::INFO, "provisioning user");
+      // the identity provider stands in for the second factor, so there's no secret to show
+      // them yet.  They can enrol one at /account/mfa
+      self.register_pending(username, None).await?;
+      Ok(true)
+    }
+    else
+    {
+      Ok(false)
+    }
   }
 
   fn hash_password(&self, password: String) -> Result<String>
@@ -146,26 +664,837 @@ impl UserManager
 
   pub async fn delete(&self, username: String) -> Result<()>
   {
-    self.get_user(username).await?.delete(&self.db).await?;
+    let user = self.get_user(username).await?;
+    security_key::Entity::delete_many()
+      .filter(security_key::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    recovery_code::Entity::delete_many()
+      .filter(recovery_code::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    GroupMember::delete_many()
+      .filter(group_member::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    Invite::delete_many()
+      .filter(invite::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    UserSession::delete_many()
+      .filter(user_session::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    oidc_authorization_code::Entity::delete_many()
+      .filter(oidc_authorization_code::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    TrustedDevice::delete_many()
+      .filter(trusted_device::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    user.delete(&self.db).await?;
     Ok(())
   }
 
   pub async fn reset_password(&self, username: String, password: String) -> Result<()>
   {
-    let mut user: user::ActiveModel = self.get_user(username).await?.into();
+    if !self.manages_passwords()
+    {
+      return Err(eyre!("Passwords are managed by an external backend"));
+    }
+    let mut user: user::ActiveModel = self.get_user(username.clone()).await?.into();
     user.password_hash = Set(self.hash_password(password)?);
     user.update(&self.db).await?;
+    self.revoke_sessions(&username).await?;
+    Ok(())
+  }
+
+  /// Logs the user out everywhere.  Sessions remember the epoch they were started in, so
+  /// bumping it also catches sessions from before they were tracked
+  pub async fn revoke_sessions(&self, username: &str) -> Result<()>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    let epoch = user.session_epoch.unwrap_or(0).wrapping_add(1);
+    let mut user: user::ActiveModel = user.into();
+    user.session_epoch = Set(Some(epoch));
+    user.update(&self.db).await?;
+    UserSession::delete_many()
+      .filter(user_session::Column::Username.eq(username))
+      .exec(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  /// Records a login so the session can be listed and revoked, returning the id it is tracked
+  /// by
+  pub async fn track_session(
+    &self,
+    username: &str,
+    ip: IpAddr,
+    user_agent: Option<&str>,
+  ) -> Result<String>
+  {
+    let id =
+      base32::encode(Alphabet::Crockford, &fill_bytes::<_, 10>(&mut thread_rng())).to_lowercase();
+    user_session::ActiveModel {
+      id: Set(id.clone()),
+      username: Set(username.to_owned()),
+      created: Set(now()),
+      last_seen: Set(now()),
+      ip: Set(ip.to_string()),
+      user_agent: Set(user_agent.map(str::to_owned)),
+    }
+    .insert(&self.db)
+    .await?;
+    Ok(id)
+  }
+
+  /// Whether a tracked session is still live, noting that it was just seen
+  pub async fn touch_session(&self, id: &str, username: &str) -> Result<bool>
+  {
+    let session = match UserSession::find_by_id(id.to_owned())
+      .filter(user_session::Column::Username.eq(username))
+      .one(&self.db)
+      .await?
+    {
+      Some(session) => session,
+      None => return Ok(false),
+    };
+    // written at most once a minute, so validating a request isn't always a database write
+    if now() - session.last_seen >= 60
+    {
+      let mut session: user_session::ActiveModel = session.into();
+      session.last_seen = Set(now());
+      session.update(&self.db).await?;
+    }
+    Ok(true)
+  }
+
+  pub async fn end_session(&self, id: &str) -> Result<()>
+  {
+    UserSession::delete_by_id(id.to_owned())
+      .exec(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn list_sessions(&self, username: &str) -> Result<Vec<SessionSummary>>
+  {
+    Ok(
+      UserSession::find()
+        .filter(user_session::Column::Username.eq(username))
+        .order_by_asc(user_session::Column::Created)
+        .all(&self.db)
+        .await?
+        .into_iter()
+        .map(|session| SessionSummary {
+          id: session.id,
+          minutes_since_created: (now() - session.created) / 60,
+          minutes_since_seen: (now() - session.last_seen) / 60,
+          ip: session.ip,
+          user_agent: session.user_agent,
+        })
+        .collect(),
+    )
+  }
+
+  /// Ends one of a user's sessions, leaving the rest logged in
+  pub async fn revoke_session(&self, username: &str, id: &str) -> Result<()>
+  {
+    let result = UserSession::delete_many()
+      .filter(user_session::Column::Id.eq(id))
+      .filter(user_session::Column::Username.eq(username))
+      .exec(&self.db)
+      .await?;
+    if result.rows_affected == 0
+    {
+      return Err(eyre!("Session {} not found for {}", id, username));
+    }
+    Ok(())
+  }
+
+  /// Forgets sessions that have sat idle long enough for the session store to expire them, or
+  /// have outlived the maximum lifetime
+  pub async fn prune_sessions(
+    &self,
+    idle_seconds: Option<u64>,
+    max_lifetime_seconds: Option<u64>,
+  ) -> Result<()>
+  {
+    if idle_seconds.is_none() && max_lifetime_seconds.is_none()
+    {
+      return Ok(());
+    }
+    let mut expired = Condition::any();
+    if let Some(idle_seconds) = idle_seconds
+    {
+      expired =
+        expired.add(user_session::Column::LastSeen.lt(now().saturating_sub(idle_seconds as i64)));
+    }
+    if let Some(max_lifetime_seconds) = max_lifetime_seconds
+    {
+      expired = expired
+        .add(user_session::Column::Created.lt(now().saturating_sub(max_lifetime_seconds as i64)));
+    }
+    UserSession::delete_many()
+      .filter(expired)
+      .exec(&self.db)
+      .await?;
     Ok(())
   }
 
-  pub async fn reset_mfa(&self, username: String) -> Result<SetupCode>
+  /// Forgets every session, for when the session store has lost them all
+  pub async fn forget_sessions(&self) -> Result<()>
   {
-    let secret = TotpSecret::new();
+    UserSession::delete_many().exec(&self.db).await?;
+    Ok(())
+  }
+
+  pub fn remembers_devices(&self) -> bool
+  {
+    self.mfa.remember_device_days.is_some()
+  }
+
+  /// Remembers the browser a user just logged in from, returning the signed token for its cookie
+  /// and how many seconds it lasts, or None if devices aren't remembered
+  pub async fn remember_device(
+    &self,
+    username: &str,
+    user_agent: Option<&str>,
+  ) -> Result<Option<(String, u64)>>
+  {
+    let lifetime = match self.mfa.remember_device_days
+    {
+      Some(days) => days as i64 * 24 * 60 * 60,
+      None => return Ok(None),
+    };
+    let id =
+      base32::encode(Alphabet::Crockford, &fill_bytes::<_, 10>(&mut thread_rng())).to_lowercase();
+    let expires = now() + lifetime;
+    trusted_device::ActiveModel {
+      id: Set(id.clone()),
+      username: Set(username.to_owned()),
+      created: Set(now()),
+      expires: Set(expires),
+      user_agent: Set(user_agent.map(str::to_owned)),
+    }
+    .insert(&self.db)
+    .await?;
+    let token = encode(
+      &Header::new(Algorithm::HS256),
+      &DeviceClaims {
+        sub: username.to_owned(),
+        jti: id,
+        exp: expires,
+      },
+      &EncodingKey::from_secret(&self.pepper),
+    )?;
+    Ok(Some((token, lifetime as u64)))
+  }
+
+  /// Whether a device token was signed by us for this user and hasn't been revoked or expired
+  async fn trusted_device(&self, username: &str, token: &str) -> Result<bool>
+  {
+    if !self.remembers_devices()
+    {
+      return Ok(false);
+    }
+    let claims = match decode::<DeviceClaims>(
+      token,
+      &DecodingKey::from_secret(&self.pepper),
+      &Validation::new(Algorithm::HS256),
+    )
+    {
+      Ok(data) => data.claims,
+      Err(_) => return Ok(false),
+    };
+    if claims.sub != username
+    {
+      return Ok(false);
+    }
+    Ok(
+      TrustedDevice::find_by_id(claims.jti)
+        .filter(trusted_device::Column::Username.eq(username))
+        .filter(trusted_device::Column::Expires.gt(now()))
+        .one(&self.db)
+        .await?
+        .is_some(),
+    )
+  }
+
+  pub async fn list_devices(&self, username: &str) -> Result<Vec<DeviceSummary>>
+  {
+    Ok(
+      TrustedDevice::find()
+        .filter(trusted_device::Column::Username.eq(username))
+        .order_by_asc(trusted_device::Column::Created)
+        .all(&self.db)
+        .await?
+        .into_iter()
+        .map(|device| DeviceSummary {
+          id: device.id,
+          days_since_created: (now() - device.created) / (24 * 60 * 60),
+          days_remaining: (device.expires - now()) / (24 * 60 * 60),
+          user_agent: device.user_agent,
+        })
+        .collect(),
+    )
+  }
+
+  /// Forgets one of a user's devices, so it needs a one time password again
+  pub async fn revoke_device(&self, username: &str, id: &str) -> Result<()>
+  {
+    let result = TrustedDevice::delete_many()
+      .filter(trusted_device::Column::Id.eq(id))
+      .filter(trusted_device::Column::Username.eq(username))
+      .exec(&self.db)
+      .await?;
+    if result.rows_affected == 0
+    {
+      return Err(eyre!("Device {} not found for {}", id, username));
+    }
+    Ok(())
+  }
+
+  pub async fn revoke_devices(&self, username: &str) -> Result<()>
+  {
+    TrustedDevice::delete_many()
+      .filter(trusted_device::Column::Username.eq(username))
+      .exec(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn prune_devices(&self) -> Result<()>
+  {
+    TrustedDevice::delete_many()
+      .filter(trusted_device::Column::Expires.lte(now()))
+      .exec(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn session_epoch(&self, username: &str) -> Result<i64>
+  {
+    Ok(
+      self
+        .get_user(username.to_owned())
+        .await?
+        .session_epoch
+        .unwrap_or(0),
+    )
+  }
+
+  pub async fn set_email(&self, username: String, email: Option<String>) -> Result<()>
+  {
+    let mut user: user::ActiveModel = self.get_user(username).await?.into();
+    user.email = Set(email);
+    user.update(&self.db).await?;
+    Ok(())
+  }
+
+  /// Looks up what upstream applications are told about a logged in user,
+  /// returning None if the user has since been deleted
+  pub async fn get_profile(&self, username: &str) -> Result<Option<Profile>>
+  {
+    let user = match User::find_by_id(username.to_owned()).one(&self.db).await?
+    {
+      Some(user) => user,
+      None => return Ok(None),
+    };
+    let groups = user
+      .find_related(Group)
+      .all(&self.db)
+      .await?
+      .into_iter()
+      .map(|group| group.name)
+      .collect();
+    Ok(Some(Profile {
+      email: user.email,
+      groups,
+      session_epoch: user.session_epoch.unwrap_or(0),
+    }))
+  }
+
+  pub async fn add_group(&self, name: String) -> Result<()>
+  {
+    // group names are joined with commas in the groups header
+    if name.is_empty() || name.contains(',')
+    {
+      return Err(eyre!(
+        "Group names must be non-empty and may not contain commas"
+      ));
+    }
+    group::ActiveModel { name: Set(name) }
+      .insert(&self.db)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn delete_group(&self, name: String) -> Result<()>
+  {
+    let group = Group::find_by_id(name.clone())
+      .one(&self.db)
+      .await?
+      .ok_or_else(|| eyre!("Group {} not found!", name))?;
+    GroupMember::delete_many()
+      .filter(group_member::Column::GroupName.eq(group.name.as_str()))
+      .exec(&self.db)
+      .await?;
+    group.delete(&self.db).await?;
+    Ok(())
+  }
+
+  pub async fn list_groups(&self) -> Result<Vec<(String, Vec<String>)>>
+  {
+    let members = GroupMember::find().all(&self.db).await?;
+    Ok(
+      Group::find()
+        .all(&self.db)
+        .await?
+        .into_iter()
+        .map(|group| {
+          let usernames = members
+            .iter()
+            .filter(|member| member.group_name == group.name)
+            .map(|member| member.username.clone())
+            .collect();
+          (group.name, usernames)
+        })
+        .collect(),
+    )
+  }
+
+  pub async fn add_to_group(&self, username: String, group: String) -> Result<()>
+  {
+    let user = self.get_user(username).await?;
+    let group = Group::find_by_id(group.clone())
+      .one(&self.db)
+      .await?
+      .ok_or_else(|| eyre!("Group {} not found!", group))?;
+    group_member::ActiveModel {
+      username: Set(user.username),
+      group_name: Set(group.name),
+    }
+    .insert(&self.db)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn remove_from_group(&self, username: String, group: String) -> Result<()>
+  {
+    let removed = GroupMember::delete_by_id((username.clone(), group.clone()))
+      .exec(&self.db)
+      .await?
+      .rows_affected;
+    if removed == 0
+    {
+      return Err(eyre!("User {} is not in group {}!", username, group));
+    }
+    Ok(())
+  }
+
+  pub async fn reset_mfa(&self, username: String) -> Result<(SetupCode, RecoveryCodes)>
+  {
+    let secret = TotpSecret::new(&self.mfa);
     let setup_code = secret.get_setup_code(&username, &self.issuer);
     let mut user: user::ActiveModel = self.get_user(username).await?.into();
-    user.totp_secret = Set(secret.into());
+    secret.store(&mut user);
+    let user = user.update(&self.db).await?;
+    // a remembered device stands in for the old secret, which may have been lost with it
+    self.revoke_devices(&user.username).await?;
+    Ok((
+      setup_code,
+      self.replace_recovery_codes(&user.username).await?,
+    ))
+  }
+
+  /// Clears the user's TOTP secret and recovery codes so they set up new ones on their next
+  /// login
+  pub async fn require_enrollment(&self, username: String) -> Result<()>
+  {
+    let mut user: user::ActiveModel = self.get_user(username).await?.into();
+    user.totp_secret = Set(Vec::new());
+    user.last_totp_step = Set(None);
+    TotpSecret::new(&self.mfa).store_pending(&mut user);
+    let user = user.update(&self.db).await?;
+    recovery_code::Entity::delete_many()
+      .filter(recovery_code::Column::Username.eq(user.username.as_str()))
+      .exec(&self.db)
+      .await?;
+    self.revoke_devices(&user.username).await?;
+    Ok(())
+  }
+
+  pub async fn totp_enrolled(&self, username: &str) -> Result<bool>
+  {
+    Ok(
+      !self
+        .get_user(username.to_owned())
+        .await?
+        .totp_secret
+        .is_empty(),
+    )
+  }
+
+  /// The setup code for the user's pending secret, generating one if they don't have one yet
+  pub async fn pending_setup_code(&self, username: &str) -> Result<SetupCode>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    let secret = match TotpSecret::pending(&user, &self.mfa)
+    {
+      Some(secret) => secret,
+      None =>
+      {
+        let secret = TotpSecret::new(&self.mfa);
+        let mut user: user::ActiveModel = user.into();
+        secret.store_pending(&mut user);
+        user.update(&self.db).await?;
+        secret
+      }
+    };
+    Ok(secret.get_setup_code(username, &self.issuer))
+  }
+
+  /// The pending secret and the step the passcode was generated at, if it was generated from
+  /// the pending secret
+  fn match_pending_totp(&self, user: &user::Model, passcode: &str) -> Option<(TotpSecret, u64)>
+  {
+    let secret = TotpSecret::pending(user, &self.mfa)?;
+    let step = secret.current_step();
+    let step = (step.saturating_sub(self.mfa.totp_skew)..=step.saturating_add(self.mfa.totp_skew))
+      .find(|step| secret.generate(*step) == passcode)?;
+    Some((secret, step))
+  }
+
+  /// Whether the passcode shows the user's authenticator has the pending secret, without
+  /// confirming it
+  #[instrument(skip(self, passcode))]
+  pub async fn pending_totp_matches(&self, username: &str, passcode: &str) -> Result<bool>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    Ok(self.match_pending_totp(&user, passcode).is_some())
+  }
+
+  /// Replaces the active secret with the pending one, provided the passcode shows the user's
+  /// authenticator has it
+  #[instrument(skip(self, passcode))]
+  pub async fn confirm_pending_totp(&self, username: &str, passcode: &str) -> Result<bool>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    let (secret, step) = match self.match_pending_totp(&user, passcode)
+    {
+      Some(matched) => matched,
+      None => return Ok(false),
+    };
+    let mut user: user::ActiveModel = user.into();
+    secret.store(&mut user);
+    // the code used to confirm can't then be used to log in
+    user.last_totp_step = Set(Some(step as i64));
+    TotpSecret::clear_pending(&mut user);
     user.update(&self.db).await?;
-    Ok(setup_code)
+    Ok(true)
+  }
+
+  pub async fn regenerate_recovery_codes(&self, username: String) -> Result<RecoveryCodes>
+  {
+    let user = self.get_user(username).await?;
+    self.replace_recovery_codes(&user.username).await
+  }
+
+  async fn replace_recovery_codes(&self, username: &str) -> Result<RecoveryCodes>
+  {
+    let codes = RecoveryCodes::new();
+    let hashes = codes
+      .get_codes()
+      .iter()
+      .map(|code| -> Result<_> {
+        let code = RecoveryCodes::normalize(code);
+        let (label, secret) = RecoveryCodes::split(&code);
+        Ok(recovery_code::ActiveModel {
+          username: Set(username.to_owned()),
+          label: Set(label.map(str::to_owned)),
+          code_hash: Set(self.hash_password(secret.to_owned())?),
+          ..Default::default()
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let txn = self.db.begin().await?;
+    recovery_code::Entity::delete_many()
+      .filter(recovery_code::Column::Username.eq(username))
+      .exec(&txn)
+      .await?;
+    recovery_code::Entity::insert_many(hashes)
+      .exec(&txn)
+      .await?;
+    txn.commit().await?;
+    Ok(codes)
+  }
+
+  /// Finds which of a user's recovery codes this is, without using it up.  The label picks out
+  /// the hash to check, and `dummy_hash` is checked when there isn't one, so unknown users and
+  /// spent codes take as long as a real code.  Codes from before labels were added are checked
+  /// against each of the user's unlabelled hashes
+  async fn match_recovery_code(
+    &self,
+    username: &str,
+    code: &str,
+    dummy_hash: &str,
+  ) -> Result<Option<i64>>
+  {
+    let code = RecoveryCodes::normalize(code);
+    let (label, secret) = RecoveryCodes::split(&code);
+    let hasher = create_hasher(&self.pepper)?;
+    let query = recovery_code::Entity::find().filter(recovery_code::Column::Username.eq(username));
+    let models = match label
+    {
+      Some(label) => query.filter(recovery_code::Column::Label.eq(label)),
+      None => query.filter(recovery_code::Column::Label.is_null()),
+    }
+    .all(&self.db)
+    .await?;
+    if models.is_empty()
+    {
+      let _ = hasher.verify_password(secret.as_bytes(), &PasswordHash::new(dummy_hash)?);
+      return Ok(None);
+    }
+    for model in models
+    {
+      if hasher
+        .verify_password(secret.as_bytes(), &PasswordHash::new(&model.code_hash)?)
+        .is_ok()
+      {
+        return Ok(Some(model.id));
+      }
+    }
+    Ok(None)
+  }
+
+  async fn redeem_recovery_code(&self, id: i64) -> Result<bool>
+  {
+    // only the node that actually deletes the row gets to use the code
+    let redeemed = recovery_code::Entity::delete_by_id(id)
+      .exec(&self.db)
+      .await?
+      .rows_affected
+      == 1;
+    if redeemed
+    {
+      event!(tracing::Level::INFO, "recovery code redeemed");
+    }
+    Ok(redeemed)
+  }
+
+  pub fn security_keys_enabled(&self) -> bool
+  {
+    self.webauthn.is_some()
+  }
+
+  fn webauthn(&self) -> Result<&Webauthn>
+  {
+    self
+      .webauthn
+      .as_deref()
+      .ok_or_else(|| eyre!("Security keys are not enabled"))
+  }
+
+  fn webauthn_user_id(username: &str) -> Result<Uuid>
+  {
+    // webauthn wants a stable opaque handle, so derive one from the username
+    Ok(Uuid::from_slice(
+      &Sha512::digest(username.as_bytes())[..16],
+    )?)
+  }
+
+  async fn get_security_keys(
+    &self,
+    username: &str,
+  ) -> Result<Vec<(security_key::Model, SecurityKey)>>
+  {
+    security_key::Entity::find()
+      .filter(security_key::Column::Username.eq(username))
+      .all(&self.db)
+      .await?
+      .into_iter()
+      .map(|model| -> Result<_> {
+        let key: SecurityKey = serde_json::from_str(&model.credential)?;
+        Ok((model, key))
+      })
+      .collect()
+  }
+
+  pub async fn list_security_keys(&self, username: String) -> Result<Vec<String>>
+  {
+    let user = self.get_user(username).await?;
+    Ok(
+      self
+        .get_security_keys(&user.username)
+        .await?
+        .into_iter()
+        .map(|(model, _)| model.name)
+        .collect(),
+    )
+  }
+
+  pub async fn remove_security_key(&self, username: String, name: String) -> Result<()>
+  {
+    let result = security_key::Entity::delete_many()
+      .filter(security_key::Column::Username.eq(username.as_str()))
+      .filter(security_key::Column::Name.eq(name.as_str()))
+      .exec(&self.db)
+      .await?;
+    if result.rows_affected == 0
+    {
+      Err(eyre!(
+        "Security key {} not found for user {}!",
+        name,
+        username
+      ))
+    }
+    else
+    {
+      Ok(())
+    }
+  }
+
+  pub async fn start_security_key_registration(
+    &self,
+    username: &str,
+  ) -> Result<(CreationChallengeResponse, SecurityKeyRegistration)>
+  {
+    let existing = self
+      .get_security_keys(username)
+      .await?
+      .into_iter()
+      .map(|(_, key)| key.cred_id().clone())
+      .collect();
+    Ok(self.webauthn()?.start_securitykey_registration(
+      Self::webauthn_user_id(username)?,
+      username,
+      username,
+      Some(existing),
+      None,
+      None,
+    )?)
+  }
+
+  #[instrument(skip(self, credential, state))]
+  pub async fn finish_security_key_registration(
+    &self,
+    username: String,
+    name: String,
+    credential: &RegisterPublicKeyCredential,
+    state: &SecurityKeyRegistration,
+  ) -> Result<()>
+  {
+    let key = self
+      .webauthn()?
+      .finish_securitykey_registration(credential, state)?;
+    security_key::ActiveModel {
+      username: Set(username),
+      name: Set(name),
+      credential: Set(serde_json::to_string(&key)?),
+      ..Default::default()
+    }
+    .insert(&self.db)
+    .await?;
+    event!(tracing::Level::INFO, "registered new security key");
+    Ok(())
+  }
+
+  pub async fn start_security_key_authentication(
+    &self,
+    username: &str,
+  ) -> Result<Option<(RequestChallengeResponse, SecurityKeyAuthentication)>>
+  {
+    let keys: Vec<SecurityKey> = self
+      .get_security_keys(username)
+      .await?
+      .into_iter()
+      .map(|(_, key)| key)
+      .collect();
+    if keys.is_empty()
+    {
+      Ok(None)
+    }
+    else
+    {
+      Ok(Some(
+        self.webauthn()?.start_securitykey_authentication(&keys)?,
+      ))
+    }
+  }
+
+  /// A challenge for users who don't exist or have no security keys, so asking for one doesn't
+  /// reveal which usernames do.  A stored key serves as the template, with its credential ID
+  /// swapped for one derived from the username so repeated requests agree, and it goes through
+  /// webauthn-rs so the response is laid out exactly like a real one.  While nobody has a key
+  /// there is nothing to hide, and nobody gets a challenge
+  pub async fn decoy_security_key_challenge(
+    &self,
+    username: &str,
+  ) -> Result<Option<RequestChallengeResponse>>
+  {
+    let template = match security_key::Entity::find()
+      .order_by_asc(security_key::Column::Id)
+      .one(&self.db)
+      .await?
+    {
+      Some(template) => template,
+      None => return Ok(None),
+    };
+    let mut hasher = Sha512::new();
+    hasher.update(&self.pepper);
+    hasher.update(username.as_bytes());
+    let seed = hasher.finalize();
+    let mut credential: serde_json::Value = serde_json::from_str(&template.credential)?;
+    if !replace_credential_id(&mut credential, &seed)
+    {
+      return Err(eyre!("stored security key has no credential ID"));
+    }
+    let key: SecurityKey = serde_json::from_value(credential)?;
+    let (challenge, _) = self.webauthn()?.start_securitykey_authentication(&[key])?;
+    Ok(Some(challenge))
+  }
+
+  async fn validate_security_key(
+    &self,
+    username: &str,
+    credential: &PublicKeyCredential,
+    challenged_username: &str,
+    state: &SecurityKeyAuthentication,
+  ) -> Result<bool>
+  {
+    // the challenge only lists the keys of the user it was issued for
+    if username != challenged_username
+    {
+      return Ok(false);
+    }
+    let result = match self
+      .webauthn()?
+      .finish_securitykey_authentication(credential, state)
+    {
+      Ok(result) => result,
+      Err(err) =>
+      {
+        event!(tracing::Level::INFO, "{}", err.to_string());
+        return Ok(false);
+      }
+    };
+
+    // persist the signature counter so cloned keys can be detected
+    for (model, mut key) in self.get_security_keys(username).await?
+    {
+      if key.update_credential(&result) == Some(true)
+      {
+        let mut model: security_key::ActiveModel = model.into();
+        model.credential = Set(serde_json::to_string(&key)?);
+        model.update(&self.db).await?;
+      }
+    }
+    Ok(true)
   }
 
   fn create_fake_user(&self) -> Result<user::Model>
@@ -173,46 +1502,195 @@ impl UserManager
     Ok(user::Model {
       username: "kevin".to_owned(),
       password_hash: self.hash_password("hunter2".to_owned())?,
-      totp_secret: vec![0; 128],
+      totp_secret: vec![0; self.mfa.totp_secret_bytes],
+      totp_algorithm: Some(algorithm_name(self.mfa.totp_algorithm).to_owned()),
+      totp_digits: Some(self.mfa.totp_digits as i32),
+      totp_period: Some(self.mfa.totp_period as i64),
+      last_totp_step: None,
+      email: None,
+      session_epoch: None,
+      pending_totp_secret: None,
+      pending_totp_algorithm: None,
+      pending_totp_digits: None,
+      pending_totp_period: None,
     })
   }
 
-  #[instrument(skip(self, password, passcode))]
-  pub async fn validate(&self, username: String, password: &str, passcode: &str) -> Result<bool>
+  fn match_totp(&self, user: &user::Model, passcode: &str) -> Option<i64>
+  {
+    // a user who has yet to enroll has no secret, and an empty key still produces codes
+    if user.totp_secret.is_empty()
+    {
+      return None;
+    }
+    let secret = TotpSecret::from_user(user);
+    let step = secret.current_step();
+    (step.saturating_sub(self.mfa.totp_skew)..=step.saturating_add(self.mfa.totp_skew))
+      .filter(|step| user.last_totp_step.map_or(true, |last| *step as i64 > last))
+      .find(|step| secret.generate(*step) == passcode)
+      .map(|step| step as i64)
+  }
+
+  async fn consume_totp_step(&self, username: &str, step: i64) -> Result<bool>
+  {
+    // conditional update so two nodes racing on the same code can't both accept it
+    let result = User::update_many()
+      .col_expr(user::Column::LastTotpStep, Expr::value(step))
+      .filter(user::Column::Username.eq(username))
+      .filter(
+        Condition::any()
+          .add(user::Column::LastTotpStep.is_null())
+          .add(user::Column::LastTotpStep.lt(step)),
+      )
+      .exec(&self.db)
+      .await?;
+    Ok(result.rows_affected == 1)
+  }
+
+  async fn check_password(&self, user: &user::Model, username: &str, password: &str)
+    -> Result<bool>
+  {
+    Ok(match &self.password_backend
+    {
+      PasswordBackend::Local =>
+      {
+        let known_hash = PasswordHash::new(&user.password_hash)?;
+        match create_hasher(&self.pepper)?.verify_password(password.as_bytes(), &known_hash)
+        {
+          Err(err) =>
+          {
+            event!(tracing::Level::INFO, "{}", err.to_string());
+            false
+          }
+          Ok(_) => true,
+        }
+      }
+      // bind even for unknown users so the response time doesn't give them away
+      PasswordBackend::Ldap(settings) => ldap::authenticate(settings, username, password).await?,
+    })
+  }
+
+  /// Checks a user's password on its own, for when the second factor is dealt with separately
+  #[instrument(skip(self, password))]
+  pub async fn verify_password(&self, username: &str, password: &str) -> Result<bool>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    self.check_password(&user, username, password).await
+  }
+
+  /// Whether this is a user who has yet to set up TOTP, logging in with their password
+  #[instrument(skip(self, password))]
+  pub async fn validate_enrollment(&self, username: &str, password: &str) -> Result<bool>
+  {
+    match User::find_by_id(username.to_owned()).one(&self.db).await?
+    {
+      Some(user) if user.totp_secret.is_empty() && user.pending_totp_secret.is_some() =>
+      {
+        self.check_password(&user, username, password).await
+      }
+      _ => Ok(false),
+    }
+  }
+
+  /// Checks a one time password from the user's active secret, burning its time step
+  #[instrument(skip(self, passcode))]
+  pub async fn validate_totp(&self, username: &str, passcode: &str) -> Result<bool>
+  {
+    let user = self.get_user(username.to_owned()).await?;
+    match self.match_totp(&user, passcode)
+    {
+      Some(step) => self.consume_totp_step(username, step).await,
+      None => Ok(false),
+    }
+  }
+
+  #[instrument(skip(self, password, second_factor))]
+  pub async fn validate(
+    &self,
+    username: String,
+    password: &str,
+    second_factor: SecondFactor<'_>,
+  ) -> Result<bool>
   {
     // get the user, or get a fake one if we got a bad username
-    let user = User::find_by_id(username).one(&self.db).await?;
+    let user = User::find_by_id(username.clone()).one(&self.db).await?;
     let faked = user.is_none();
     let fake_user = self.create_fake_user()?;
+    let dummy_hash = fake_user.password_hash.clone();
     let user = user.unwrap_or(fake_user);
 
-    // validate the totp
-    let seconds: u64 = SystemTime::now()
-      .duration_since(UNIX_EPOCH)
-      .unwrap()
-      .as_secs();
-    let passcode_valid =
-      totp_custom::<Sha1>(DEFAULT_STEP, 6, &user.totp_secret, seconds) == passcode;
-
-    // validate the password
-    let known_hash = PasswordHash::new(&user.password_hash)?;
-    let password_valid = match create_hasher(&self.pepper)?
-      .verify_password(password.as_bytes(), &known_hash)
+    // validate the totp or security key
+    let mut totp_step = None;
+    let mut recovery_code_id = None;
+    let second_factor_valid = match second_factor
     {
-      Err(err) =>
+      SecondFactor::Passcode(passcode) if passcode.chars().all(|c| c.is_ascii_digit()) =>
       {
-        event!(tracing::Level::INFO, "{}", err.to_string());
-        false
+        totp_step = self.match_totp(&user, passcode);
+        totp_step.is_some()
+      }
+      // the fake user has no codes, but a hash is checked all the same
+      SecondFactor::Passcode(recovery_code) =>
+      {
+        recovery_code_id = self
+          .match_recovery_code(&user.username, recovery_code, &dummy_hash)
+          .await?;
+        !faked && recovery_code_id.is_some()
+      }
+      SecondFactor::SecurityKey {
+        credential,
+        challenged_username,
+        state,
+      } =>
+      {
+        !faked
+          && self
+            .validate_security_key(&user.username, credential, challenged_username, state)
+            .await?
+      }
+      // users who still have to enroll can't skip it with a device remembered beforehand
+      SecondFactor::TrustedDevice(token) =>
+      {
+        !faked && !user.totp_secret.is_empty() && self.trusted_device(&user.username, token).await?
       }
-      Ok(_) => true,
     };
+
+    // validate the password
+    let password_valid = self.check_password(&user, &username, password).await?;
     event!(
       tracing::Level::INFO,
-      "username found: {}, passcode valid: {}, password valid: {}",
+      "username found: {}, second factor valid: {}, password valid: {}",
       !faked,
-      passcode_valid,
+      second_factor_valid,
       password_valid
     );
-    Ok(!faked && passcode_valid && password_valid)
+    let valid = !faked && second_factor_valid && password_valid;
+
+    // a time step or recovery code is only burned once the whole login has succeeded
+    match (totp_step, recovery_code_id)
+    {
+      (Some(step), _) if valid =>
+      {
+        let fresh = self.consume_totp_step(&user.username, step).await?;
+        if !fresh
+        {
+          event!(tracing::Level::WARN, "rejected replayed one time password");
+        }
+        Ok(fresh)
+      }
+      (_, Some(id)) if valid =>
+      {
+        let fresh = self.redeem_recovery_code(id).await?;
+        if !fresh
+        {
+          event!(
+            tracing::Level::WARN,
+            "rejected recovery code used concurrently"
+          );
+        }
+        Ok(fresh)
+      }
+      _ => Ok(valid),
+    }
   }
 }
@@ -18,33 +18,59 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use askama::Template;
 use axum::{
-  extract::Query,
-  headers::{self, Header, HeaderName},
-  http::{HeaderValue, StatusCode},
-  response::Redirect,
+  extract::{Query, RawQuery},
+  headers::{
+    self,
+    authorization::{Basic, Bearer},
+    Authorization, Header, HeaderName,
+  },
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Redirect, Response},
   routing::{get, post},
-  Extension, Form, Router, TypedHeader,
+  Extension, Form, Json, Router, TypedHeader,
 };
 use axum_server::tls_rustls::RustlsConfig;
-use axum_sessions::extractors::WritableSession;
-use color_eyre::eyre::{Context, Result};
+use axum_sessions::{async_session::serde_json, extractors::WritableSession};
+use base64::{engine::general_purpose, Engine};
+use color_eyre::eyre::{eyre, Context, Result};
 use hyperlocal::UnixServerExt;
-use serde::Deserialize;
+use ipnet::IpNet;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
 use std::{
   fmt::{Debug, Display},
   iter::once,
+  net::IpAddr,
   time::Duration,
 };
 use tokio::{join, spawn, task, time};
 use tracing::{event, instrument};
+use url::form_urlencoded;
+use webauthn_rs::prelude::{
+  CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential,
+  RequestChallengeResponse, SecurityKeyAuthentication, SecurityKeyRegistration,
+};
 
 use crate::{
-  challenge_manager::{Base64Image, ChallengeManager},
-  config::BindTo,
-  session::{RouterExt, SessionBackendStorage},
-  user_manager::UserManager,
+  access::{AccessPolicy, Target},
+  challenge_manager::{Base64Image, Challenge, ChallengeManager, ProofOfWork, TokenScope},
+  client_addr::{Client, PeerAddr, TrustedProxies},
+  config::{BindTo, HeaderSettings, SessionBinding},
+  oidc::{
+    redirect_with, AuthorizationCheck, AuthorizationRequest, JwkSet, OidcProvider,
+    ProviderMetadata, TokenError, TokenRequest, UserInfo,
+  },
+  redirect::RedirectPolicy,
+  session::{RouterExt, SessionBackendStorage, WritableSessionExt},
+  upstream::{UpstreamLoginState, UpstreamProvider},
+  user_manager::{now, password_strong_enough, Profile, SecondFactor, UserManager},
 };
 
+const DEVICE_COOKIE: &str = "ruuth_device";
+
+/// How recently a one time password or security key must have been checked to register a key
+const SECURITY_KEY_VERIFY_SECONDS: u64 = 600;
+
 #[derive(Deserialize, Debug)]
 struct LoginResponse
 {
@@ -53,6 +79,9 @@ struct LoginResponse
   password: String,
   passcode: String,
   captcha: Option<String>,
+  proof: Option<String>,
+  security_key: Option<String>,
+  remember_device: Option<String>,
 }
 
 #[derive(Template)]
@@ -61,9 +90,140 @@ struct LoginChallengeRequest
 {
   authenticity_token: String,
   captcha: Option<Base64Image>,
+  captcha_audio: bool,
+  proof_of_work: Option<ProofOfWork>,
   url: Option<String>,
   error: Option<bool>,
   realm: String,
+  security_keys: bool,
+  remember_device: bool,
+  device_remembered: bool,
+  upstream: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "security_keys.html")]
+struct SecurityKeysPage
+{
+  username: String,
+  keys: Vec<String>,
+  realm: String,
+}
+
+#[derive(Template)]
+#[template(path = "account_password.html")]
+struct ChangePasswordPage
+{
+  authenticity_token: String,
+  username: String,
+  realm: String,
+  error: Option<&'static str>,
+  changed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "verify.html")]
+struct VerifyPage
+{
+  authenticity_token: String,
+  username: String,
+  url: Option<String>,
+  realm: String,
+  error: Option<&'static str>,
+}
+
+#[derive(Template)]
+#[template(path = "account_mfa.html")]
+struct MfaPage
+{
+  authenticity_token: String,
+  username: String,
+  realm: String,
+  qr_code: String,
+  setup_code: String,
+  current_passcode_required: bool,
+  error: Option<&'static str>,
+}
+
+#[derive(Template)]
+#[template(path = "account_mfa_done.html")]
+struct MfaEnrolledPage
+{
+  realm: String,
+  recovery_codes: Option<Vec<String>>,
+  url: String,
+}
+
+#[derive(Deserialize)]
+struct MfaRequest
+{
+  authenticity_token: String,
+  current_passcode: Option<String>,
+  passcode: String,
+}
+
+#[derive(Template)]
+#[template(path = "invite.html")]
+struct InvitePage
+{
+  authenticity_token: String,
+  token: String,
+  /// None when the invite is invalid, expired or used
+  username: Option<String>,
+  realm: String,
+  choose_password: bool,
+  error: Option<&'static str>,
+}
+
+#[derive(Deserialize)]
+struct InviteQuery
+{
+  token: String,
+}
+
+#[derive(Deserialize)]
+struct InviteRequest
+{
+  authenticity_token: String,
+  token: String,
+  password: Option<String>,
+  confirm_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest
+{
+  authenticity_token: String,
+  current_password: String,
+  passcode: String,
+  new_password: String,
+  confirm_password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerifyRequest
+{
+  authenticity_token: String,
+  passcode: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SecurityKeyChallengeRequest
+{
+  username: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SecurityKeyRegistrationResponse
+{
+  name: String,
+  credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize, Debug)]
+struct OidcErrorResponse
+{
+  error: &'static str,
 }
 
 #[derive(Deserialize, Debug)]
@@ -72,6 +232,14 @@ struct LoginQuery
   url: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+struct UpstreamCallbackQuery
+{
+  code: Option<String>,
+  state: Option<String>,
+  error: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct ChallengeQuery
 {
@@ -79,6 +247,12 @@ struct ChallengeQuery
   error: Option<bool>,
 }
 
+#[derive(Deserialize, Debug)]
+struct ValidateQuery
+{
+  max_auth_age: Option<u64>,
+}
+
 macro_rules! header {
   ($struct_name:ident, $header_value:expr) => {
     struct $struct_name(String);
@@ -110,7 +284,8 @@ macro_rules! header {
   };
 }
 
-header!(XForwardedFor, "x-forwarded-for");
+header!(XOriginalUrl, "x-original-url");
+header!(XMaxAuthAge, "x-max-auth-age");
 
 trait TracedError<T, E: Display>: Sized
 {
@@ -128,12 +303,75 @@ impl<T, E: Display> TracedError<T, E> for Result<T, E>
   }
 }
 
+/// Header values can only hold visible ASCII, so anything else is percent-encoded.  Percent
+/// signs and commas are too, so values decode unambiguously and groups split cleanly
+const HEADER_ENCODE: &AsciiSet = &CONTROLS.add(b'%').add(b',');
+
+fn header_value(value: &str) -> Result<HeaderValue, StatusCode>
+{
+  HeaderValue::from_str(&utf8_percent_encode(value, HEADER_ENCODE).to_string()).trace_error()
+}
+
+/// Whether the session's second factor was checked within the last `max_age` seconds
+fn verified_within(session: &WritableSession, max_age: u64) -> bool
+{
+  session
+    .get::<i64>("verified_at")
+    .map_or(false, |verified_at| {
+      now().saturating_sub(verified_at) <= max_age as i64
+    })
+}
+
+fn authenticated_user(session: &WritableSession) -> Option<String>
+{
+  if session
+    .get::<bool>("logged_in")
+    .map_or(false, |logged_in| logged_in)
+  {
+    session.get::<String>("username")
+  }
+  else
+  {
+    None
+  }
+}
+
+#[derive(Clone)]
+struct RemoteHeaders
+{
+  user: HeaderName,
+  groups: HeaderName,
+  email: HeaderName,
+}
+
+impl TryFrom<HeaderSettings> for RemoteHeaders
+{
+  type Error = color_eyre::Report;
+
+  fn try_from(settings: HeaderSettings) -> Result<Self>
+  {
+    Ok(Self {
+      user: HeaderName::try_from(settings.user).wrap_err("invalid user header name")?,
+      groups: HeaderName::try_from(settings.groups).wrap_err("invalid groups header name")?,
+      email: HeaderName::try_from(settings.email).wrap_err("invalid email header name")?,
+    })
+  }
+}
+
 #[derive(Clone)]
 pub struct WebServer<const N: usize>
 {
   user_manager: UserManager,
   challenge_manager: ChallengeManager<N>,
+  oidc: Option<OidcProvider>,
+  upstream: Option<UpstreamProvider>,
+  headers: RemoteHeaders,
+  access_policy: AccessPolicy,
+  redirect_policy: RedirectPolicy,
+  trusted_proxies: TrustedProxies,
   session_timeout_seconds: Option<u64>,
+  max_session_lifetime_seconds: Option<u64>,
+  session_binding: SessionBinding,
   realm: String,
 }
 
@@ -142,31 +380,104 @@ impl<const N: usize> WebServer<N>
   pub fn new(
     user_manager: UserManager,
     challenge_manager: ChallengeManager<N>,
+    oidc: Option<OidcProvider>,
+    upstream: Option<UpstreamProvider>,
+    headers: HeaderSettings,
+    access_policy: AccessPolicy,
+    redirect_policy: RedirectPolicy,
+    trusted_proxies: TrustedProxies,
     session_timeout_seconds: Option<u64>,
+    max_session_lifetime_seconds: Option<u64>,
+    session_binding: SessionBinding,
     realm: String,
-  ) -> Self
+  ) -> Result<Self>
   {
-    Self {
+    if session_binding
+      .ipv4_prefix
+      .map_or(false, |prefix| prefix > 32)
+      || session_binding
+        .ipv6_prefix
+        .map_or(false, |prefix| prefix > 128)
+    {
+      return Err(eyre!(
+        "session ipv4_prefix must be at most 32 and ipv6_prefix at most 128"
+      ));
+    }
+    Ok(Self {
       user_manager,
       challenge_manager,
+      oidc,
+      upstream,
+      headers: headers.try_into()?,
+      access_policy,
+      redirect_policy,
+      trusted_proxies,
       session_timeout_seconds,
+      max_session_lifetime_seconds,
+      session_binding,
       realm,
-    }
+    })
   }
 
   #[instrument(skip(self, storage, bind_to))]
   pub async fn run(self, storage: SessionBackendStorage, bind_to: BindTo) -> Result<()>
   {
     let challenge_manager = self.challenge_manager.clone();
+    let user_manager = self.user_manager.clone();
+    let session_timeout_seconds = self.session_timeout_seconds;
+    let max_session_lifetime_seconds = self.max_session_lifetime_seconds;
     let router = Router::new()
       .route("/login", post(Self::login_handler))
+      .route("/verify", post(Self::verify_handler))
       .route("/logout", post(Self::logout_handler))
       .route("/", get(Self::auth_handler))
+      .route("/captcha/audio", get(Self::captcha_audio_handler))
       .route("/validate", get(Self::validate_handler))
+      .route(
+        "/account/password",
+        get(Self::change_password_page_handler).post(Self::change_password_handler),
+      )
+      .route(
+        "/invite",
+        get(Self::invite_page_handler).post(Self::invite_handler),
+      )
+      .route(
+        "/account/mfa",
+        get(Self::mfa_page_handler).post(Self::mfa_handler),
+      )
+      .route("/security-keys", get(Self::security_keys_handler))
+      .route(
+        "/security-keys/register/start",
+        post(Self::security_key_registration_start_handler),
+      )
+      .route(
+        "/security-keys/register/finish",
+        post(Self::security_key_registration_finish_handler),
+      )
+      .route(
+        "/security-keys/authenticate",
+        post(Self::security_key_authentication_handler),
+      )
+      .route(
+        "/.well-known/openid-configuration",
+        get(Self::oidc_discovery_handler),
+      )
+      .route("/oidc/authorize", get(Self::oidc_authorize_handler))
+      .route("/oidc/token", post(Self::oidc_token_handler))
+      .route("/oidc/jwks", get(Self::oidc_jwks_handler))
+      .route("/oidc/userinfo", get(Self::oidc_userinfo_handler))
+      .route("/upstream/login", get(Self::upstream_login_handler))
+      .route("/upstream/callback", get(Self::upstream_callback_handler))
       .layer_session(storage.clone())
+      .layer(Extension(self.trusted_proxies.clone()))
       .layer(Extension(self));
 
     storage.migrate().await?;
+    // whatever sessions were recorded before the restart are gone
+    if storage.is_in_memory()
+    {
+      user_manager.forget_sessions().await?;
+    }
     let cleanup = task::spawn(async move {
       let mut interval = time::interval(Duration::from_secs(3600));
       loop
@@ -176,13 +487,24 @@ impl<const N: usize> WebServer<N>
         {
           event!(tracing::Level::ERROR, "{}", error);
         }
+        if let Err(error) = user_manager
+          .prune_sessions(session_timeout_seconds, max_session_lifetime_seconds)
+          .await
+        {
+          event!(tracing::Level::ERROR, "{}", error);
+        }
+        if let Err(error) = user_manager.prune_devices().await
+        {
+          event!(tracing::Level::ERROR, "{}", error);
+        }
       }
     });
 
-    let service = router.into_make_service();
-    let (cleanup, challenge_cleanup, server) = join!(
+    let service = router.into_make_service_with_connect_info::<PeerAddr>();
+    let (cleanup, challenge_cleanup, reload, server) = join!(
       spawn(cleanup),
       spawn(challenge_manager.cleanup_task()),
+      spawn(challenge_manager.reload_task()),
       match bind_to
       {
         BindTo::Tls {
@@ -222,115 +544,1377 @@ impl<const N: usize> WebServer<N>
 
     cleanup??;
     challenge_cleanup??;
+    reload??;
     server??;
 
     Ok(())
   }
 
-  #[instrument(skip(this, form))]
+  #[instrument(skip(this, cookies, form))]
   async fn login_handler(
     Extension(this): Extension<Self>,
     mut session: WritableSession,
-    TypedHeader(XForwardedFor(origin_host)): TypedHeader<XForwardedFor>,
+    client: Client,
+    cookies: Option<TypedHeader<headers::Cookie>>,
     query: Query<LoginQuery>,
     form: Form<LoginResponse>,
-  ) -> Result<Redirect, StatusCode>
+  ) -> Result<Response, StatusCode>
   {
-    if this
+    if this.challenge_manager.forbidden(client.ip)
+    {
+      return Err(StatusCode::FORBIDDEN);
+    }
+    let challenge =
+      session.take::<(String, SecurityKeyAuthentication)>("security_key_authentication");
+    let credential = form
+      .security_key
+      .as_deref()
+      .filter(|credential| !credential.is_empty())
+      .and_then(|credential| serde_json::from_str::<PublicKeyCredential>(credential).ok());
+    let second_factor = match (&credential, &challenge)
+    {
+      (Some(credential), Some((challenged_username, state))) => SecondFactor::SecurityKey {
+        credential,
+        challenged_username,
+        state,
+      },
+      _ => match cookies
+        .as_ref()
+        .and_then(|TypedHeader(cookies)| cookies.get(DEVICE_COOKIE))
+      {
+        // a remembered device only stands in for a passcode that was left blank
+        Some(token) if form.passcode.is_empty() => SecondFactor::TrustedDevice(token),
+        _ => SecondFactor::Passcode(&form.passcode),
+      },
+    };
+    let remembered = matches!(second_factor, SecondFactor::TrustedDevice(_));
+    let challenge_passed = this
       .challenge_manager
       .validate(
         &mut session,
         &form.authenticity_token,
         &form.captcha,
-        &origin_host,
+        &form.proof,
+        client.ip,
+        &form.username,
       )
       .await
-      .trace_error()?
+      .trace_error()?;
+    // the password is still checked so a failed challenge takes as long, but without a second
+    // factor so nothing single use is spent on a login that's going to be refused
+    let second_factor = if challenge_passed
+    {
+      second_factor
+    }
+    else
+    {
+      SecondFactor::Passcode("")
+    };
+    if challenge_passed
       & this
         .user_manager
-        .validate(form.username.clone(), &form.password, &form.passcode)
+        .validate(form.username.clone(), &form.password, second_factor)
         .await
         .trace_error()?
     {
-      session.regenerate();
-      session.insert("logged_in", true).trace_error()?;
-      this.extend_session(&mut session);
-      Ok(Redirect::to(match &query.url
+      this.log_in(&mut session, &form.username, &client).await?;
+      let redirect = Redirect::to(&this.redirect_policy.sanitize(query.url.as_deref()));
+      // a remembered device skipped the second factor, so it doesn't count as verifying it
+      if remembered
+      {
+        return Ok(redirect.into_response());
+      }
+      session.insert("verified_at", now()).trace_error()?;
+      if form.remember_device.is_none()
+      {
+        return Ok(redirect.into_response());
+      }
+      match this
+        .user_manager
+        .remember_device(&form.username, client.user_agent.as_deref())
+        .await
+        .trace_error()?
       {
-        Some(url) => &url,
-        None => "/",
-      }))
+        Some((token, lifetime)) => Ok(
+          (
+            [(
+              header::SET_COOKIE,
+              format!(
+                "{}={}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Strict",
+                DEVICE_COOKIE, token, lifetime
+              ),
+            )],
+            redirect,
+          )
+            .into_response(),
+        ),
+        None => Ok(redirect.into_response()),
+      }
+    }
+    else if challenge_passed
+      && this
+        .user_manager
+        .validate_enrollment(&form.username, &form.password)
+        .await
+        .trace_error()?
+    {
+      // not logged in until a code from the new secret has been entered
+      session.regenerate();
+      session.insert("enrolling", &form.username).trace_error()?;
+      session.insert("enrolling_url", &query.url).trace_error()?;
+      Ok(Redirect::to("/account/mfa").into_response())
     }
     else
     {
       this
         .challenge_manager
-        .add_failure(origin_host)
+        .add_failure(client.ip, form.username.clone())
         .await
         .trace_error()?;
-      Ok(Redirect::to("/?error=true"))
+      Ok(Redirect::to("/?error=true").into_response())
     }
   }
 
-  #[instrument]
-  async fn logout_handler(mut session: WritableSession) -> Result<(), StatusCode>
+  #[instrument(skip(this))]
+  async fn logout_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+  ) -> Result<(), StatusCode>
   {
+    if let Some(id) = session.take::<String>("session_id")
+    {
+      this.user_manager.end_session(&id).await.trace_error()?;
+    }
     session.insert("logged_in", false).trace_error()?;
+    session.remove("username");
+    session.remove("verified_at");
+    session.regenerate();
+    Ok(())
+  }
+
+  async fn log_in(
+    &self,
+    session: &mut WritableSession,
+    username: &str,
+    client: &Client,
+  ) -> Result<(), StatusCode>
+  {
+    let epoch = self
+      .user_manager
+      .session_epoch(username)
+      .await
+      .trace_error()?;
+    let session_id = self
+      .user_manager
+      .track_session(username, client.ip, client.user_agent.as_deref())
+      .await
+      .trace_error()?;
     session.regenerate();
+    // regenerating keeps the data, and a verification from before this login, perhaps by
+    // someone else on a shared browser, says nothing about this one
+    session.remove("verified_at");
+    session.insert("logged_in", true).trace_error()?;
+    session.insert("username", username).trace_error()?;
+    session.insert("session_epoch", epoch).trace_error()?;
+    session.insert("session_id", session_id).trace_error()?;
+    session.insert("logged_in_at", now()).trace_error()?;
+    // kept whether or not sessions are bound, so binding can be turned on for sessions already
+    // logged in
+    session.insert("login_ip", client.ip).trace_error()?;
+    session
+      .insert("login_user_agent", client.user_agent.as_deref())
+      .trace_error()?;
+    self.extend_session(session);
     Ok(())
   }
 
+  /// The logged in user, unless they've been deleted, their sessions revoked since this one
+  /// started, or the session is being used from somewhere it isn't bound to
+  async fn current_profile(
+    &self,
+    session: &WritableSession,
+    client: &Client,
+  ) -> Result<Option<(String, Profile)>, StatusCode>
+  {
+    let username = match authenticated_user(session)
+    {
+      Some(username) => username,
+      None => return Ok(None),
+    };
+    if self.lifetime_remaining(session) == Some(0)
+    {
+      return Ok(None);
+    }
+    if !self.fingerprint_matches(session, client)
+    {
+      event!(
+        tracing::Level::WARN,
+        "session for {} used from {} ({:?}), which doesn't match its login",
+        username,
+        client.ip,
+        client.user_agent
+      );
+      return Ok(None);
+    }
+    // sessions from before they were tracked can still be revoked through the epoch
+    if let Some(id) = session.get::<String>("session_id")
+    {
+      if !self
+        .user_manager
+        .touch_session(&id, &username)
+        .await
+        .trace_error()?
+      {
+        return Ok(None);
+      }
+    }
+    let epoch = session.get::<i64>("session_epoch").unwrap_or(0);
+    Ok(
+      self
+        .user_manager
+        .get_profile(&username)
+        .await
+        .trace_error()?
+        .filter(|profile| profile.session_epoch == epoch)
+        .map(|profile| (username, profile)),
+    )
+  }
+
+  async fn current_user(
+    &self,
+    session: &WritableSession,
+    client: &Client,
+  ) -> Result<Option<String>, StatusCode>
+  {
+    Ok(
+      self
+        .current_profile(session, client)
+        .await?
+        .map(|(username, _)| username),
+    )
+  }
+
+  /// Seconds left before the session must be logged into again.  Sessions that predate the login
+  /// timestamp are treated as already expired
+  fn lifetime_remaining(&self, session: &WritableSession) -> Option<u64>
+  {
+    self.max_session_lifetime_seconds.map(|lifetime| {
+      let logged_in_at = session.get::<i64>("logged_in_at").unwrap_or(0);
+      (logged_in_at.saturating_add(lifetime as i64) - now()).max(0) as u64
+    })
+  }
+
+  /// Whether a request comes from the same network and browser the session logged in with, as
+  /// far as sessions are bound to either
+  fn fingerprint_matches(&self, session: &WritableSession, client: &Client) -> bool
+  {
+    let login_ip = session.get::<IpAddr>("login_ip");
+    let prefix = match login_ip
+    {
+      Some(IpAddr::V4(_)) => self.session_binding.ipv4_prefix,
+      Some(IpAddr::V6(_)) => self.session_binding.ipv6_prefix,
+      None => self
+        .session_binding
+        .ipv4_prefix
+        .or(self.session_binding.ipv6_prefix),
+    };
+    let network_matches = match (prefix, login_ip)
+    {
+      (None, _) => true,
+      (Some(prefix), Some(login_ip)) =>
+      {
+        IpNet::new(login_ip, prefix).map_or(false, |network| network.contains(&client.ip))
+      }
+      // sessions from before the address was recorded can't show they match
+      (Some(_), None) => false,
+    };
+    let user_agent_matches = !self.session_binding.user_agent
+      || session
+        .get::<Option<String>>("login_user_agent")
+        .map_or(false, |login_user_agent| {
+          login_user_agent == client.user_agent
+        });
+    network_matches && user_agent_matches
+  }
+
   fn extend_session(&self, session: &mut WritableSession)
   {
-    if let Some(expires) = self.session_timeout_seconds
+    let remaining = if authenticated_user(session).is_some()
+    {
+      self.lifetime_remaining(session)
+    }
+    else
+    {
+      None
+    };
+    let expires = match (self.session_timeout_seconds, remaining)
+    {
+      (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+      (timeout, remaining) => timeout.or(remaining),
+    };
+    if let Some(expires) = expires
     {
       session.expire_in(Duration::from_secs(expires));
     }
   }
 
-  #[instrument(skip(this))]
+  #[instrument(skip(this, original_url, host))]
   async fn validate_handler(
     Extension(this): Extension<Self>,
     mut session: WritableSession,
-  ) -> StatusCode
+    original_url: Option<TypedHeader<XOriginalUrl>>,
+    host: Option<TypedHeader<headers::Host>>,
+    max_auth_age: Option<TypedHeader<XMaxAuthAge>>,
+    client: Client,
+    Query(query): Query<ValidateQuery>,
+  ) -> Result<Response, StatusCode>
   {
     this.extend_session(&mut session);
-    if session
-      .get::<bool>("logged_in")
-      .map_or(false, |logged_in| logged_in)
-    {
-      event!(tracing::Level::TRACE, "Auth passed");
-      StatusCode::OK
-    }
-    else
+    match this.current_profile(&session, &client).await?
     {
-      event!(tracing::Level::TRACE, "Auth failed");
-      StatusCode::UNAUTHORIZED
+      Some((username, profile)) =>
+      {
+        let target = Target::new(
+          original_url
+            .as_ref()
+            .map(|TypedHeader(XOriginalUrl(url))| url.as_str()),
+          host.as_ref().map(|TypedHeader(host)| host.hostname()),
+        );
+        if !this.access_policy.permits(&target, &profile.groups)
+        {
+          event!(
+            tracing::Level::INFO,
+            "{} denied access to {:?}",
+            username,
+            target
+          );
+          return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+        let max_auth_age = query.max_auth_age.or_else(|| {
+          max_auth_age
+            .as_ref()
+            .and_then(|TypedHeader(XMaxAuthAge(age))| age.parse().ok())
+        });
+        if let Some(max_auth_age) = max_auth_age
+        {
+          if !verified_within(&session, max_auth_age)
+          {
+            event!(
+              tracing::Level::INFO,
+              "{} must verify again to access {:?}",
+              username,
+              target
+            );
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+          }
+        }
+
+        event!(tracing::Level::TRACE, "Auth passed");
+        let mut headers = HeaderMap::new();
+        headers.insert(this.headers.user.clone(), header_value(&username)?);
+        if !profile.groups.is_empty()
+        {
+          let groups = profile
+            .groups
+            .iter()
+            .map(|group| utf8_percent_encode(group, HEADER_ENCODE).to_string())
+            .collect::<Vec<_>>();
+          headers.insert(
+            this.headers.groups.clone(),
+            HeaderValue::from_str(&groups.join(",")).trace_error()?,
+          );
+        }
+        if let Some(email) = profile.email
+        {
+          headers.insert(this.headers.email.clone(), header_value(&email)?);
+        }
+        Ok((StatusCode::OK, headers).into_response())
+      }
+      None =>
+      {
+        event!(tracing::Level::TRACE, "Auth failed");
+        Ok(StatusCode::UNAUTHORIZED.into_response())
+      }
     }
   }
 
-  #[instrument(skip(this))]
+  #[instrument(skip(this, cookies))]
   //#[axum_macros::debug_handler]
   async fn auth_handler(
     Extension(this): Extension<Self>,
     mut session: WritableSession,
-    TypedHeader(XForwardedFor(origin_host)): TypedHeader<XForwardedFor>,
+    client: Client,
+    cookies: Option<TypedHeader<headers::Cookie>>,
     query: Query<ChallengeQuery>,
-  ) -> Result<impl askama_axum::IntoResponse, StatusCode>
+  ) -> Result<Response, StatusCode>
   {
-    Ok(LoginChallengeRequest {
-      authenticity_token: this
-        .challenge_manager
-        .issue_challenge(&mut session)
-        .trace_error()?,
-      captcha: this
-        .challenge_manager
-        .maybe_issue_captcha(&mut session, &origin_host)
-        .await
-        .trace_error()?,
-      url: query.url.clone(),
-      error: query.error.clone(),
-      realm: this.realm.clone(),
-    })
+    if this.challenge_manager.forbidden(client.ip)
+    {
+      return Err(StatusCode::FORBIDDEN);
+    }
+    // a logged in user sent back here was turned away for not having verified recently enough.
+    // One who moved somewhere their session isn't bound to isn't logged in as far as this is
+    // concerned, so gets a full login
+    if query.url.is_some()
+    {
+      if let Some(username) = this.current_user(&session, &client).await?
+      {
+        if this
+          .user_manager
+          .totp_enrolled(&username)
+          .await
+          .trace_error()?
+        {
+          return this
+            .verify_page(&mut session, username, query.url.clone(), None)
+            .await;
+        }
+      }
+    }
+    let (captcha, proof_of_work) = match this
+      .challenge_manager
+      .maybe_issue_challenge(&mut session, client.ip)
+      .await
+      .trace_error()?
+    {
+      Some(Challenge::Captcha(captcha)) => (Some(captcha), None),
+      Some(Challenge::ProofOfWork(proof_of_work)) => (None, Some(proof_of_work)),
+      None => (None, None),
+    };
+    Ok(
+      LoginChallengeRequest {
+        authenticity_token: this
+          .challenge_manager
+          .issue_challenge(&mut session, TokenScope::Login)
+          .trace_error()?,
+        captcha_audio: captcha.is_some() && this.challenge_manager.captcha_audio_enabled(),
+        captcha,
+        proof_of_work,
+        url: query.url.clone(),
+        error: query.error.clone(),
+        realm: this.realm.clone(),
+        security_keys: this.user_manager.security_keys_enabled(),
+        remember_device: this.user_manager.remembers_devices(),
+        device_remembered: this.user_manager.remembers_devices()
+          && cookies.as_ref().map_or(false, |TypedHeader(cookies)| {
+            cookies.get(DEVICE_COOKIE).is_some()
+          }),
+        upstream: this
+          .upstream
+          .as_ref()
+          .map(|upstream| upstream.name().to_owned()),
+      }
+      .into_response(),
+    )
+  }
+
+  async fn verify_page(
+    &self,
+    session: &mut WritableSession,
+    username: String,
+    url: Option<String>,
+    error: Option<&'static str>,
+  ) -> Result<Response, StatusCode>
+  {
+    Ok(
+      VerifyPage {
+        authenticity_token: self
+          .challenge_manager
+          .issue_challenge(session, TokenScope::Verify)
+          .trace_error()?,
+        username,
+        url,
+        realm: self.realm.clone(),
+        error,
+      }
+      .into_response(),
+    )
+  }
+
+  /// Checks a one time password again for a user who is already logged in, without asking for
+  /// their password
+  #[instrument(skip(this, form))]
+  async fn verify_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    query: Query<LoginQuery>,
+    Form(form): Form<VerifyRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    let username = match this.current_user(&session, &client).await?
+    {
+      Some(username) => username,
+      None => return Ok(Redirect::to("/?error=true").into_response()),
+    };
+    if !this.challenge_manager.validate_token(
+      &mut session,
+      TokenScope::Verify,
+      &form.authenticity_token,
+    )
+    {
+      return Err(StatusCode::BAD_REQUEST);
+    }
+    let error = if this
+      .challenge_manager
+      .throttled(client.ip, &username)
+      .await
+      .trace_error()?
+    {
+      Some("Too many failed attempts.  Please try again later")
+    }
+    else if !this
+      .user_manager
+      .validate_totp(&username, form.passcode.trim())
+      .await
+      .trace_error()?
+    {
+      this
+        .challenge_manager
+        .add_failure(client.ip, username.clone())
+        .await
+        .trace_error()?;
+      Some("That code is incorrect.  Please try again")
+    }
+    else
+    {
+      None
+    };
+    match error
+    {
+      Some(error) =>
+      {
+        this
+          .verify_page(&mut session, username, query.url.clone(), Some(error))
+          .await
+      }
+      None =>
+      {
+        session.insert("verified_at", now()).trace_error()?;
+        Ok(Redirect::to(&this.redirect_policy.sanitize(query.url.as_deref())).into_response())
+      }
+    }
+  }
+
+  #[instrument(skip(this))]
+  async fn captcha_audio_handler(
+    Extension(this): Extension<Self>,
+    session: WritableSession,
+  ) -> Result<Response, StatusCode>
+  {
+    match this
+      .challenge_manager
+      .captcha_audio(&session)
+      .trace_error()?
+    {
+      Some(wav) => Ok(
+        (
+          [
+            (header::CONTENT_TYPE, "audio/wav"),
+            (header::CACHE_CONTROL, "no-store"),
+          ],
+          wav,
+        )
+          .into_response(),
+      ),
+      None => Err(StatusCode::NOT_FOUND),
+    }
+  }
+
+  #[instrument(skip(this))]
+  async fn change_password_page_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+  ) -> Result<Response, StatusCode>
+  {
+    if !this.user_manager.manages_passwords()
+    {
+      return Err(StatusCode::NOT_FOUND);
+    }
+    match this.current_user(&session, &client).await?
+    {
+      Some(username) => Ok(
+        ChangePasswordPage {
+          authenticity_token: this
+            .challenge_manager
+            .issue_challenge(&mut session, TokenScope::Password)
+            .trace_error()?,
+          username,
+          realm: this.realm.clone(),
+          error: None,
+          changed: false,
+        }
+        .into_response(),
+      ),
+      None => Ok(Redirect::to("/?url=/account/password").into_response()),
+    }
+  }
+
+  #[instrument(skip(this, form))]
+  async fn change_password_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    Form(form): Form<ChangePasswordRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    if !this.user_manager.manages_passwords()
+    {
+      return Err(StatusCode::NOT_FOUND);
+    }
+    let username = this
+      .current_user(&session, &client)
+      .await?
+      .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !this.challenge_manager.validate_token(
+      &mut session,
+      TokenScope::Password,
+      &form.authenticity_token,
+    )
+    {
+      return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let error = if this
+      .challenge_manager
+      .throttled(client.ip, &username)
+      .await
+      .trace_error()?
+    {
+      Some("Too many failed attempts.  Please try again later")
+    }
+    // checked before the one time password is spent, so a typo doesn't mean waiting for the
+    // next one
+    else if form.new_password != form.confirm_password
+    {
+      Some("New passwords do not match")
+    }
+    else if !password_strong_enough(&form.new_password, &[&username, &this.realm])
+    {
+      Some("New password is too weak.  Try adding another word or two")
+    }
+    // only a fresh one time password will do here, not a recovery code
+    else if !form.passcode.chars().all(|c| c.is_ascii_digit())
+      || !this
+        .user_manager
+        .validate(
+          username.clone(),
+          &form.current_password,
+          SecondFactor::Passcode(&form.passcode),
+        )
+        .await
+        .trace_error()?
+    {
+      this
+        .challenge_manager
+        .add_failure(client.ip, username.clone())
+        .await
+        .trace_error()?;
+      Some("Current password or one time password is incorrect")
+    }
+    else
+    {
+      // also logs out every session, this one included
+      this
+        .user_manager
+        .reset_password(username.clone(), form.new_password)
+        .await
+        .trace_error()?;
+      // but this session proved it knows the password, so start it again
+      this.log_in(&mut session, &username, &client).await?;
+      session.insert("verified_at", now()).trace_error()?;
+      None
+    };
+
+    Ok(
+      ChangePasswordPage {
+        authenticity_token: this
+          .challenge_manager
+          .issue_challenge(&mut session, TokenScope::Password)
+          .trace_error()?,
+        username,
+        realm: this.realm.clone(),
+        changed: error.is_none(),
+        error,
+      }
+      .into_response(),
+    )
+  }
+
+  async fn invite_page(
+    &self,
+    session: &mut WritableSession,
+    token: String,
+    error: Option<&'static str>,
+  ) -> Result<Response, StatusCode>
+  {
+    Ok(
+      InvitePage {
+        authenticity_token: self
+          .challenge_manager
+          .issue_challenge(session, TokenScope::Invite)
+          .trace_error()?,
+        username: self.user_manager.find_invite(&token).await.trace_error()?,
+        token,
+        realm: self.realm.clone(),
+        choose_password: self.user_manager.manages_passwords(),
+        error,
+      }
+      .into_response(),
+    )
+  }
+
+  #[instrument(skip(this, query))]
+  async fn invite_page_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    Query(query): Query<InviteQuery>,
+  ) -> Result<Response, StatusCode>
+  {
+    this.invite_page(&mut session, query.token, None).await
+  }
+
+  #[instrument(skip(this, form))]
+  async fn invite_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    Form(form): Form<InviteRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    if !this.challenge_manager.validate_token(
+      &mut session,
+      TokenScope::Invite,
+      &form.authenticity_token,
+    )
+    {
+      return Err(StatusCode::BAD_REQUEST);
+    }
+    let username = match this
+      .user_manager
+      .find_invite(&form.token)
+      .await
+      .trace_error()?
+    {
+      Some(username) => username,
+      None => return this.invite_page(&mut session, form.token, None).await,
+    };
+
+    let password = form.password.unwrap_or_default();
+    if this.user_manager.manages_passwords()
+    {
+      let error = if Some(&password) != form.confirm_password.as_ref()
+      {
+        Some("Passwords do not match")
+      }
+      else if !password_strong_enough(&password, &[&username, &this.realm])
+      {
+        Some("Password is too weak.  Try adding another word or two")
+      }
+      else
+      {
+        None
+      };
+      if error.is_some()
+      {
+        return this.invite_page(&mut session, form.token, error).await;
+      }
+    }
+    // the link alone mustn't be enough to log in as someone whose password lives elsewhere
+    else
+    {
+      let error = if this
+        .challenge_manager
+        .throttled(client.ip, &username)
+        .await
+        .trace_error()?
+      {
+        Some("Too many failed attempts.  Please try again later")
+      }
+      else if !this
+        .user_manager
+        .verify_password(&username, &password)
+        .await
+        .trace_error()?
+      {
+        this
+          .challenge_manager
+          .add_failure(client.ip, username.clone())
+          .await
+          .trace_error()?;
+        Some("Password is incorrect")
+      }
+      else
+      {
+        None
+      };
+      if error.is_some()
+      {
+        return this.invite_page(&mut session, form.token, error).await;
+      }
+    }
+
+    match this
+      .user_manager
+      .redeem_invite(&form.token, password)
+      .await
+      .trace_error()?
+    {
+      Some(username) =>
+      {
+        // the account isn't usable until it has a TOTP secret, so go straight to enrollment
+        session.regenerate();
+        session.insert("enrolling", &username).trace_error()?;
+        Ok(Redirect::to("/account/mfa").into_response())
+      }
+      None => this.invite_page(&mut session, form.token, None).await,
+    }
+  }
+
+  /// Either a logged in user rotating their secret, or one setting up their first after
+  /// passing the password check at login
+  async fn mfa_user(
+    &self,
+    session: &WritableSession,
+    client: &Client,
+  ) -> Result<Option<(String, bool)>, StatusCode>
+  {
+    if let Some(username) = self.current_user(session, client).await?
+    {
+      return Ok(Some((username, false)));
+    }
+    Ok(
+      session
+        .get::<String>("enrolling")
+        .map(|username| (username, true)),
+    )
+  }
+
+  async fn mfa_page(
+    &self,
+    session: &mut WritableSession,
+    username: String,
+    error: Option<&'static str>,
+  ) -> Result<Response, StatusCode>
+  {
+    let setup_code = self
+      .user_manager
+      .pending_setup_code(&username)
+      .await
+      .trace_error()?;
+    let qr_code = setup_code.get_svg_qr_code().trace_error()?;
+    Ok(
+      MfaPage {
+        authenticity_token: self
+          .challenge_manager
+          .issue_challenge(session, TokenScope::Mfa)
+          .trace_error()?,
+        current_passcode_required: self
+          .user_manager
+          .totp_enrolled(&username)
+          .await
+          .trace_error()?,
+        username,
+        realm: self.realm.clone(),
+        qr_code: general_purpose::STANDARD.encode(qr_code),
+        setup_code: setup_code.get_raw_code(),
+        error,
+      }
+      .into_response(),
+    )
+  }
+
+  #[instrument(skip(this))]
+  async fn mfa_page_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+  ) -> Result<Response, StatusCode>
+  {
+    match this.mfa_user(&session, &client).await?
+    {
+      Some((username, _)) => this.mfa_page(&mut session, username, None).await,
+      None => Ok(Redirect::to("/?url=/account/mfa").into_response()),
+    }
+  }
+
+  #[instrument(skip(this, form))]
+  async fn mfa_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    Form(form): Form<MfaRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    let (username, enrolling) = this
+      .mfa_user(&session, &client)
+      .await?
+      .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !this.challenge_manager.validate_token(
+      &mut session,
+      TokenScope::Mfa,
+      &form.authenticity_token,
+    )
+    {
+      return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if this
+      .challenge_manager
+      .throttled(client.ip, &username)
+      .await
+      .trace_error()?
+    {
+      return this
+        .mfa_page(
+          &mut session,
+          username,
+          Some("Too many failed attempts.  Please try again later"),
+        )
+        .await;
+    }
+    let had_secret = this
+      .user_manager
+      .totp_enrolled(&username)
+      .await
+      .trace_error()?;
+    // the new code is checked first so a mistyped one doesn't spend the current one
+    if !this
+      .user_manager
+      .pending_totp_matches(&username, form.passcode.trim())
+      .await
+      .trace_error()?
+    {
+      return this
+        .mfa_page(
+          &mut session,
+          username,
+          Some("That code doesn't match.  Check your authenticator's clock and try again"),
+        )
+        .await;
+    }
+    // replacing an existing secret needs a code from it, so a stolen session can't
+    if had_secret
+      && !this
+        .user_manager
+        .validate_totp(
+          &username,
+          form.current_passcode.as_deref().unwrap_or_default(),
+        )
+        .await
+        .trace_error()?
+    {
+      this
+        .challenge_manager
+        .add_failure(client.ip, username.clone())
+        .await
+        .trace_error()?;
+      return this
+        .mfa_page(
+          &mut session,
+          username,
+          Some("Current one time password is incorrect"),
+        )
+        .await;
+    }
+    if !this
+      .user_manager
+      .confirm_pending_totp(&username, form.passcode.trim())
+      .await
+      .trace_error()?
+    {
+      return this
+        .mfa_page(
+          &mut session,
+          username,
+          Some("That code doesn't match.  Check your authenticator's clock and try again"),
+        )
+        .await;
+    }
+
+    // whoever had no secret before has never been given recovery codes either
+    let recovery_codes = if had_secret
+    {
+      None
+    }
+    else
+    {
+      Some(
+        this
+          .user_manager
+          .regenerate_recovery_codes(username.clone())
+          .await
+          .trace_error()?
+          .get_codes()
+          .to_vec(),
+      )
+    };
+    let url = if enrolling
+    {
+      let url = session.get::<Option<String>>("enrolling_url").flatten();
+      session.remove("enrolling");
+      session.remove("enrolling_url");
+      this.log_in(&mut session, &username, &client).await?;
+      url
+    }
+    else
+    {
+      None
+    };
+    session.insert("verified_at", now()).trace_error()?;
+    Ok(
+      MfaEnrolledPage {
+        realm: this.realm.clone(),
+        recovery_codes,
+        url: this.redirect_policy.sanitize(url.as_deref()),
+      }
+      .into_response(),
+    )
+  }
+
+  #[instrument(skip(this))]
+  async fn security_keys_handler(
+    Extension(this): Extension<Self>,
+    session: WritableSession,
+    client: Client,
+  ) -> Result<Response, StatusCode>
+  {
+    match this.current_user(&session, &client).await?
+    {
+      // registering a key adds a second factor, which a stolen session alone shouldn't be able to do
+      Some(_) if !verified_within(&session, SECURITY_KEY_VERIFY_SECONDS) =>
+      {
+        Ok(Redirect::to("/?url=/security-keys").into_response())
+      }
+      Some(username) => Ok(
+        SecurityKeysPage {
+          keys: this
+            .user_manager
+            .list_security_keys(username.clone())
+            .await
+            .trace_error()?,
+          username,
+          realm: this.realm.clone(),
+        }
+        .into_response(),
+      ),
+      None => Ok(Redirect::to("/?url=/security-keys").into_response()),
+    }
+  }
+
+  #[instrument(skip(this))]
+  async fn security_key_registration_start_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+  ) -> Result<Json<CreationChallengeResponse>, StatusCode>
+  {
+    let username = this
+      .current_user(&session, &client)
+      .await?
+      .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !verified_within(&session, SECURITY_KEY_VERIFY_SECONDS)
+    {
+      return Err(StatusCode::UNAUTHORIZED);
+    }
+    let (challenge, state) = this
+      .user_manager
+      .start_security_key_registration(&username)
+      .await
+      .trace_error()?;
+    session
+      .insert("security_key_registration", state)
+      .trace_error()?;
+    Ok(Json(challenge))
+  }
+
+  #[instrument(skip(this, registration))]
+  async fn security_key_registration_finish_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    Json(registration): Json<SecurityKeyRegistrationResponse>,
+  ) -> Result<StatusCode, StatusCode>
+  {
+    let username = this
+      .current_user(&session, &client)
+      .await?
+      .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !verified_within(&session, SECURITY_KEY_VERIFY_SECONDS)
+    {
+      return Err(StatusCode::UNAUTHORIZED);
+    }
+    let state = session
+      .take::<SecurityKeyRegistration>("security_key_registration")
+      .ok_or(StatusCode::BAD_REQUEST)?;
+    this
+      .user_manager
+      .finish_security_key_registration(
+        username,
+        registration.name,
+        &registration.credential,
+        &state,
+      )
+      .await
+      .trace_error()?;
+    Ok(StatusCode::CREATED)
+  }
+
+  #[instrument(skip(this))]
+  async fn security_key_authentication_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    Json(request): Json<SecurityKeyChallengeRequest>,
+  ) -> Result<Json<RequestChallengeResponse>, StatusCode>
+  {
+    if this.challenge_manager.forbidden(client.ip)
+    {
+      return Err(StatusCode::FORBIDDEN);
+    }
+    if this
+      .challenge_manager
+      .throttled(client.ip, &request.username)
+      .await
+      .trace_error()?
+    {
+      return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    match this
+      .user_manager
+      .start_security_key_authentication(&request.username)
+      .await
+      .trace_error()?
+    {
+      Some((challenge, state)) =>
+      {
+        session
+          .insert("security_key_authentication", (request.username, state))
+          .trace_error()?;
+        Ok(Json(challenge))
+      }
+      // nothing goes in the session, so whatever is signed in answer is turned away at login
+      None =>
+      {
+        session.remove("security_key_authentication");
+        this
+          .user_manager
+          .decoy_security_key_challenge(&request.username)
+          .await
+          .trace_error()?
+          .map(Json)
+          .ok_or(StatusCode::NOT_FOUND)
+      }
+    }
+  }
+
+  fn oidc(&self) -> Result<&OidcProvider, StatusCode>
+  {
+    self.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)
+  }
+
+  #[instrument(skip(this))]
+  async fn oidc_discovery_handler(
+    Extension(this): Extension<Self>,
+  ) -> Result<Json<ProviderMetadata>, StatusCode>
+  {
+    Ok(Json(this.oidc()?.metadata()))
+  }
+
+  #[instrument(skip(this))]
+  async fn oidc_jwks_handler(Extension(this): Extension<Self>) -> Result<Json<JwkSet>, StatusCode>
+  {
+    Ok(Json(this.oidc()?.jwks().await.trace_error()?))
+  }
+
+  #[instrument(skip(this, session))]
+  async fn oidc_authorize_handler(
+    Extension(this): Extension<Self>,
+    session: WritableSession,
+    client: Client,
+    RawQuery(raw_query): RawQuery,
+    Query(request): Query<AuthorizationRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    let oidc = this.oidc()?;
+    let redirect_error = |error: &str| -> Result<Response, StatusCode> {
+      let mut params = vec![("error", error)];
+      params.extend(request.state.as_deref().map(|state| ("state", state)));
+      Ok(
+        Redirect::to(&redirect_with(&request.redirect_uri, &params).trace_error()?).into_response(),
+      )
+    };
+
+    match oidc.check_authorization(&request).await.trace_error()?
+    {
+      AuthorizationCheck::Rejected => return Err(StatusCode::BAD_REQUEST),
+      AuthorizationCheck::Error(error) => return redirect_error(error),
+      AuthorizationCheck::Accepted =>
+      {}
+    }
+
+    let username = match this.current_user(&session, &client).await?
+    {
+      Some(username) => username,
+      None =>
+      {
+        let return_to = format!("/oidc/authorize?{}", raw_query.unwrap_or_default());
+        let return_to: String = form_urlencoded::byte_serialize(return_to.as_bytes()).collect();
+        return Ok(Redirect::to(&format!("/?url={}", return_to)).into_response());
+      }
+    };
+
+    let code = oidc.issue_code(&request, &username).await.trace_error()?;
+    let mut params = vec![("code", code.as_str())];
+    params.extend(request.state.as_deref().map(|state| ("state", state)));
+    Ok(Redirect::to(&redirect_with(&request.redirect_uri, &params).trace_error()?).into_response())
+  }
+
+  #[instrument(skip(this, basic, form))]
+  async fn oidc_token_handler(
+    Extension(this): Extension<Self>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    Form(form): Form<TokenRequest>,
+  ) -> Result<Response, StatusCode>
+  {
+    let oidc = this.oidc()?;
+    let (client_id, client_secret) = match &basic
+    {
+      Some(TypedHeader(Authorization(basic))) => (Some(basic.username()), Some(basic.password())),
+      None => (None, None),
+    };
+    let no_store = [(header::CACHE_CONTROL, "no-store")];
+    match oidc
+      .exchange_code(&form, client_id, client_secret)
+      .await
+      .trace_error()?
+    {
+      Ok(tokens) => Ok((no_store, Json(tokens)).into_response()),
+      Err(error) =>
+      {
+        event!(
+          tracing::Level::INFO,
+          "token request rejected: {}",
+          error.code()
+        );
+        let status = match error
+        {
+          TokenError::InvalidClient => StatusCode::UNAUTHORIZED,
+          _ => StatusCode::BAD_REQUEST,
+        };
+        Ok(
+          (
+            status,
+            no_store,
+            Json(OidcErrorResponse {
+              error: error.code(),
+            }),
+          )
+            .into_response(),
+        )
+      }
+    }
+  }
+
+  #[instrument(skip(this, bearer))]
+  async fn oidc_userinfo_handler(
+    Extension(this): Extension<Self>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+  ) -> Result<Json<UserInfo>, StatusCode>
+  {
+    let oidc = this.oidc()?;
+    let TypedHeader(Authorization(bearer)) = bearer.ok_or(StatusCode::UNAUTHORIZED)?;
+    oidc
+      .user_info(bearer.token())
+      .await
+      .trace_error()?
+      .map(Json)
+      .ok_or(StatusCode::UNAUTHORIZED)
+  }
+
+  #[instrument(skip(this, session))]
+  async fn upstream_login_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    query: Query<LoginQuery>,
+  ) -> Result<Redirect, StatusCode>
+  {
+    let upstream = this.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if this.challenge_manager.forbidden(client.ip)
+    {
+      return Err(StatusCode::FORBIDDEN);
+    }
+    let (authorize_url, state) = upstream.start(query.url.clone());
+    session.insert("upstream_login", state).trace_error()?;
+    Ok(Redirect::to(&authorize_url))
+  }
+
+  #[instrument(skip(this, session, query))]
+  async fn upstream_callback_handler(
+    Extension(this): Extension<Self>,
+    mut session: WritableSession,
+    client: Client,
+    query: Query<UpstreamCallbackQuery>,
+  ) -> Result<Redirect, StatusCode>
+  {
+    let upstream = this.upstream.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if this.challenge_manager.forbidden(client.ip)
+    {
+      return Err(StatusCode::FORBIDDEN);
+    }
+    let state = session
+      .take::<UpstreamLoginState>("upstream_login")
+      .ok_or(StatusCode::BAD_REQUEST)?;
+    let (code, csrf_token) = match (&query.code, &query.state)
+    {
+      (Some(code), Some(csrf_token)) => (code.clone(), csrf_token.as_str()),
+      _ =>
+      {
+        event!(
+          tracing::Level::INFO,
+          "identity provider returned error {:?}",
+          query.error
+        );
+        return Ok(Redirect::to("/?error=true"));
+      }
+    };
+    let url = state.url.clone();
+
+    let username = match upstream
+      .finish(code, csrf_token, state)
+      .await
+      .trace_error()?
+    {
+      Some(username) => username,
+      None => return Ok(Redirect::to("/?error=true")),
+    };
+    // bans and lockouts apply however the user proved who they are
+    if this
+      .challenge_manager
+      .throttled(client.ip, &username)
+      .await
+      .trace_error()?
+    {
+      event!(
+        tracing::Level::INFO,
+        "upstream login for {} refused by ban or lockout",
+        username
+      );
+      return Ok(Redirect::to("/?error=true"));
+    }
+    if !this
+      .user_manager
+      .find_or_provision(username.clone(), upstream.auto_provision())
+      .await
+      .trace_error()?
+    {
+      event!(tracing::Level::INFO, "no local user for {}", username);
+      return Ok(Redirect::to("/?error=true"));
+    }
+
+    this.log_in(&mut session, &username, &client).await?;
+    Ok(Redirect::to(&this.redirect_policy.sanitize(url.as_deref())))
   }
 }